@@ -0,0 +1,144 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::env;
+use std::str::FromStr;
+use std::time::Duration;
+
+use chrono::Utc;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{Mutex, MutexGuard};
+
+use crate::error::Error;
+use crate::state;
+use crate::store::Store;
+
+lazy_static! {
+    /// Chats served regardless of the owner's list, unless denied at runtime.
+    pub static ref CHAT_LIST: HashSet<i64> = match env::var("CHAT_LIST") {
+        Ok(x) => x
+            .split(',')
+            .filter(|x| !x.is_empty())
+            .map(|x| i64::from_str(x).expect("Parsing CHAT_LIST failed"))
+            .collect(),
+        Err(_) => HashSet::new(),
+    };
+    pub static ref OWNER_ID: Option<i64> = env::var("OWNER_ID")
+        .ok()
+        .map(|x| i64::from_str(&x).expect("Parsing OWNER_ID failed"));
+    static ref CHATS: Store<Chats> = Store::open("chats.json");
+    /// Never shrinks, so a chat denied and allowed again keeps its lock.
+    static ref LOCKS: std::sync::Mutex<HashMap<i64, &'static Mutex<()>>> =
+        std::sync::Mutex::new(HashMap::new());
+}
+
+/// Load the allowed chats from disk.
+pub fn load() {
+    lazy_static::initialize(&CHATS);
+}
+
+/// Held while updating the avatar of a chat.
+pub struct ChatLock {
+    chat_id: i64,
+    _guard: MutexGuard<'static, ()>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct Chats {
+    allowed: BTreeSet<i64>,
+    /// Chats of `CHAT_LIST` denied by the owner.
+    denied: BTreeSet<i64>,
+}
+
+impl Chats {
+    fn is_allowed(&self, chat_id: i64) -> bool {
+        !self.denied.contains(&chat_id)
+            && (self.allowed.contains(&chat_id) || CHAT_LIST.contains(&chat_id))
+    }
+}
+
+pub fn is_owner(user_id: i64) -> bool {
+    *OWNER_ID == Some(user_id)
+}
+
+pub fn is_allowed(chat_id: i64) -> bool {
+    CHATS.read(|x| x.is_allowed(chat_id))
+}
+
+impl ChatLock {
+    pub fn new(chat_id: i64, guard: MutexGuard<'static, ()>) -> Self {
+        Self {
+            chat_id,
+            _guard: guard,
+        }
+    }
+
+    /// Time since the last update of the avatar, `Duration::MAX` if there is none.
+    pub fn elapsed(&self) -> Duration {
+        match state::get(self.chat_id).last_update {
+            Some(x) => (Utc::now() - x).to_std().unwrap_or_default(),
+            None => Duration::MAX,
+        }
+    }
+
+    pub fn touch(&mut self) {
+        if let Err(e) = state::update(self.chat_id, |x| x.last_update = Some(Utc::now())) {
+            println!("Failed to save last update time of {}: {e}", self.chat_id);
+        }
+    }
+}
+
+/// `None` if the chat is not served.
+pub fn lock(chat_id: i64) -> Option<&'static Mutex<()>> {
+    if !is_allowed(chat_id) {
+        return None;
+    }
+
+    let mut locks = LOCKS.lock().unwrap_or_else(|e| e.into_inner());
+    let x = locks
+        .entry(chat_id)
+        .or_insert_with(|| Box::leak(Box::new(Mutex::new(()))));
+    Some(x)
+}
+
+/// Returns `false` if the chat was already allowed.
+pub fn allow(chat_id: i64) -> Result<bool, Error> {
+    CHATS.update(|x| {
+        let allowed = x.is_allowed(chat_id);
+        x.denied.remove(&chat_id);
+        if !CHAT_LIST.contains(&chat_id) {
+            x.allowed.insert(chat_id);
+        }
+        !allowed
+    })
+}
+
+/// Returns `false` if the chat was not allowed.
+pub fn deny(chat_id: i64) -> Result<bool, Error> {
+    CHATS.update(|x| {
+        let allowed = x.is_allowed(chat_id);
+        x.allowed.remove(&chat_id);
+        if CHAT_LIST.contains(&chat_id) {
+            x.denied.insert(chat_id);
+        }
+        allowed
+    })
+}
+
+/// All served chats, with whether each comes from `CHAT_LIST`.
+pub fn list() -> Vec<(i64, bool)> {
+    CHATS.read(|x| {
+        let mut chats: Vec<_> = CHAT_LIST
+            .iter()
+            .filter(|i| !x.denied.contains(i))
+            .map(|&i| (i, true))
+            .chain(
+                x.allowed
+                    .iter()
+                    .filter(|i| !CHAT_LIST.contains(i))
+                    .map(|&i| (i, false)),
+            )
+            .collect();
+        chats.sort();
+        chats
+    })
+}
@@ -1,111 +1,111 @@
-use std::collections::HashMap;
-use std::env;
 use std::io::Cursor;
 use std::str::FromStr;
 use std::sync::Arc;
-use std::time::{Duration, Instant};
+use std::time::Duration;
 
+use chrono::{DateTime, Local, TimeDelta, Utc};
 use grammers_client::types::media::Uploaded;
 use grammers_client::types::photo_sizes::VecExt;
-use grammers_client::types::{Downloadable, Media, Message, PackedChat};
-use grammers_client::{Client, InputMessage, Update};
-use grammers_tl_types::enums::{InputChatPhoto, MessageEntity, SendMessageAction};
-use grammers_tl_types::functions::channels::EditPhoto;
-use grammers_tl_types::functions::messages::SetTyping;
-use grammers_tl_types::types::{InputChatUploadedPhoto, MessageEntityCode};
+use grammers_client::types::{CallbackQuery, Chat, Downloadable, Media, Message, PackedChat};
+use grammers_client::{button, reply_markup, Client, InputMessage, Update};
+use grammers_session::PackedType;
+use grammers_tl_types::enums::{
+    self, InputChatPhoto, InputFileLocation, MessageEntity, SendMessageAction,
+};
+use grammers_tl_types::functions::channels::{EditPhoto, GetFullChannel};
+use grammers_tl_types::functions::messages::{EditChatPhoto, GetFullChat, SetTyping};
+use grammers_tl_types::functions::upload::GetFile;
+use grammers_tl_types::types::{InputChatUploadedPhoto, InputPhotoFileLocation, MessageEntityCode};
 use lazy_static::lazy_static;
 use tokio::select;
-use tokio::sync::{Mutex, Notify};
+use tokio::sync::Notify;
 use tokio::task::spawn;
-use tokio::time::{interval, timeout};
+use tokio::time::{interval, sleep, timeout};
 
+use crate::chats::{self, ChatLock};
 use crate::error::{Error, IntoErrorMessage, Message as _};
-use crate::image::{image_to_png, tgs_to_png};
+use crate::ffmpeg::video_to_png;
+use crate::history::{self, Source};
+use crate::image::{image_extension, image_to_png, tgs_to_png};
 use crate::opengraph::link_to_img;
-use crate::video::{tgs_to_mp4, video_to_mp4};
+use crate::opt::{
+    expand_presets, parse_align, parse_color, parse_datetime, parse_duration, Frame, Opt,
+};
+use crate::pending::{self, Decision, Kind};
+use crate::pool::{self, Item};
+use crate::schedule::{self, Action, Job};
+use crate::settings::{self, Policy, Settings, Threshold, Vote};
+use crate::store::AvatarFile;
+use crate::video::{is_avatar_ready, tgs_to_mp4, video_to_mp4};
 use crate::USERNAME;
 
 const SET_TIMEOUT: Duration = Duration::from_secs(60);
 const MIN_INTERVAL: Duration = Duration::from_secs(30);
 const MAX_FILESIZE: usize = 10 * 1024 * 1024;
+const DOWNLOAD_CHUNK_SIZE: i32 = 512 * 1024;
+const SCHEDULE_INTERVAL: Duration = Duration::from_secs(10);
+const TIME_FORMAT: &str = "%Y-%m-%d %H:%M";
+const MIN_ROTATION_INTERVAL: Duration = Duration::from_secs(5 * 60);
+const DEFAULT_VOTE_WINDOW: Duration = Duration::from_secs(10 * 60);
+const MAX_VOTE_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+const APPROVAL_WINDOW: Duration = Duration::from_secs(60 * 60);
+const PREVIEW_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+const MAX_CAPTION_LENGTH: usize = 1024;
+const MAX_COMMAND_LENGTH: usize = 64;
 
-lazy_static! {
-    pub static ref LAST_UPDATE: HashMap<i64, Mutex<Instant>> = {
-        let mut last_update = HashMap::new();
-        let last = Instant::now() - MIN_INTERVAL;
-
-        for i in env::var("CHAT_LIST").expect("CHAT_LIST").split(',') {
-            last_update.insert(
-                i64::from_str(i).expect("Parsing CHAT_LIST failed"),
-                Mutex::new(last),
-            );
-        }
-
-        last_update
-    };
+#[derive(Debug)]
+enum Command {
+    Help,
+    SetAvatar(String),
+    RemoveAvatar,
+    GetAvatar,
+    History,
+    Revert(String),
+    Schedule(String),
+    Pool(String),
+    Vote(String),
+    Policy(String),
+    Settings(String),
+    Preset(String),
+    Allow(String),
+    Deny(String),
+    Chats,
 }
 
-#[derive(Clone, Copy, Debug)]
-pub enum Color {
-    Rgb([i32; 3]),
-    Trans,
+#[derive(Clone, Copy)]
+enum Review {
+    Vote(Vote),
+    Approval,
 }
 
-#[derive(Clone, Copy, Debug)]
-pub enum Align {
-    Top,
-    Bottom,
-    Center,
+pub struct Avatar {
+    pub data: Vec<u8>,
+    pub is_video: bool,
+    pub video_start_ts: Option<f64>,
+    /// The file once uploaded to Telegram, reused by later requests.
+    pub uploaded: Option<Uploaded>,
 }
 
-#[derive(Clone, Copy, Debug)]
-pub struct Opt {
-    pub color: Color,
-    pub align: Option<Align>,
-    pub dry_run: bool,
-    pub show_detect: bool,
-}
+impl Avatar {
+    pub fn extension(&self) -> &'static str {
+        if self.is_video {
+            "mp4"
+        } else {
+            image_extension(&self.data)
+        }
+    }
 
-#[derive(Debug)]
-enum Command {
-    Help,
-    SetAvatar(Opt),
+    pub fn file_name(&self) -> String {
+        format!("file.{}", self.extension())
+    }
 }
 
-impl From<&str> for Opt {
-    fn from(opt: &str) -> Self {
-        let mut color = Color::Rgb([0xff, 0xff, 0xff]);
-        let mut align = None;
-        let mut dry_run = false;
-        let mut show_detect = false;
-        for x in opt.split_whitespace().take(3) {
-            match x {
-                "t" | "top" => align = Some(Align::Top),
-                "b" | "bottom" => align = Some(Align::Bottom),
-                "c" | "center" => align = Some(Align::Center),
-                "d" | "dry" => dry_run = true,
-                "s" | "show" => {
-                    align = None;
-                    dry_run = true;
-                    show_detect = true;
-                }
-                "tr" | "trans" => color = Color::Trans,
-                x => {
-                    let [_, rgb @ ..] = u32::from_str_radix(x.trim().trim_start_matches('#'), 16)
-                        .unwrap_or(0xffffff)
-                        .to_be_bytes()
-                        .map(|x| x as _);
-                    color = Color::Rgb(rgb)
-                }
-            }
-        }
-
-        Self {
-            color,
-            align,
-            dry_run,
-            show_detect,
-        }
+fn parse_policy(policy: &str) -> Option<Policy> {
+    match policy {
+        "admins" => Some(Policy::Admins),
+        "anyone" => Some(Policy::Anyone),
+        "approval" => Some(Policy::Approval),
+        _ => None,
     }
 }
 
@@ -165,10 +165,20 @@ impl Entity for Message {
                     let opt = self.entity(x.offset + x.length, -1);
                     match command {
                         "/help" => Some(Command::Help),
-                        "/set_avatar" => {
-                            let opt = opt.trim().into();
-                            Some(Command::SetAvatar(opt))
-                        }
+                        "/set_avatar" => Some(Command::SetAvatar(opt.trim().into())),
+                        "/remove_avatar" => Some(Command::RemoveAvatar),
+                        "/get_avatar" => Some(Command::GetAvatar),
+                        "/history" => Some(Command::History),
+                        "/schedule" => Some(Command::Schedule(opt.trim().into())),
+                        "/pool" => Some(Command::Pool(opt.trim().into())),
+                        "/vote" => Some(Command::Vote(opt.trim().into())),
+                        "/policy" => Some(Command::Policy(opt.trim().into())),
+                        "/settings" => Some(Command::Settings(opt.trim().into())),
+                        "/preset" => Some(Command::Preset(opt.trim().into())),
+                        "/allow" => Some(Command::Allow(opt.trim().into())),
+                        "/deny" => Some(Command::Deny(opt.trim().into())),
+                        "/chats" => Some(Command::Chats),
+                        "/revert" => Some(Command::Revert(opt.trim().into())),
                         _ => None,
                     }
                 }
@@ -178,16 +188,188 @@ impl Entity for Message {
     }
 }
 
+fn cooldown(chat_id: i64) -> Duration {
+    match settings::get(chat_id).cooldown {
+        // settings saved before the floor was enforced may hold a shorter cooldown
+        Some(x) => Duration::from_secs(x).max(MIN_INTERVAL),
+        None => MIN_INTERVAL,
+    }
+}
+
+fn try_lock_chat(chat_id: i64) -> Result<ChatLock, Error> {
+    match chats::lock(chat_id) {
+        Some(x) => match x.try_lock() {
+            Ok(guard) => Ok(ChatLock::new(chat_id, guard)),
+            Err(_) => {
+                if pending::is_pending(chat_id) {
+                    "本群组有等待投票或审批的头像, 请稍后再试".result()
+                } else {
+                    "正在处理之前的请求, 请稍后...".result()
+                }
+            }
+        },
+        None => format!("尚未向本群组 ({chat_id}) 提供服务").result(),
+    }
+}
+
+async fn lock_chat(chat_id: i64) -> Result<ChatLock, Error> {
+    match chats::lock(chat_id) {
+        Some(x) => Ok(ChatLock::new(chat_id, x.lock().await)),
+        None => format!("尚未向本群组 ({chat_id}) 提供服务").result(),
+    }
+}
+
+fn uploaded_photo(
+    uploaded: Uploaded,
+    is_video: bool,
+    video_start_ts: Option<f64>,
+) -> InputChatPhoto {
+    let mut photo = InputChatUploadedPhoto {
+        file: None,
+        video: None,
+        video_start_ts: None,
+        video_emoji_markup: None,
+    };
+    let input_file = uploaded.into();
+    if is_video {
+        photo.video.replace(input_file);
+        photo.video_start_ts = video_start_ts;
+    } else {
+        photo.file.replace(input_file);
+    }
+
+    InputChatPhoto::InputChatUploadedPhoto(photo)
+}
+
+fn parse_threshold(threshold: &str) -> Option<Threshold> {
+    if let Some(x) = threshold.strip_suffix('%') {
+        let x = f64::from_str(x).ok().filter(|x| *x > 0.0 && *x <= 100.0)?;
+        return Some(Threshold::Fraction(x / 100.0));
+    }
+
+    usize::from_str(threshold)
+        .ok()
+        .filter(|x| *x > 0)
+        .map(Threshold::Count)
+}
+
+fn approval_markup() -> reply_markup::Inline {
+    reply_markup::inline(vec![vec![
+        button::inline("批准", "approval:yes"),
+        button::inline("拒绝", "approval:no"),
+    ]])
+}
+
+fn vote_markup(approvals: usize, rejections: usize) -> reply_markup::Inline {
+    reply_markup::inline(vec![vec![
+        button::inline(format!("同意 ({approvals})"), "vote:yes"),
+        button::inline(format!("反对 ({rejections})"), "vote:no"),
+        button::inline("取消 (管理员)", "vote:cancel"),
+    ]])
+}
+
+fn apply_markup() -> reply_markup::Inline {
+    reply_markup::inline(vec![vec![button::inline("应用", "apply")]])
+}
+
+fn chat_name(chat: &Chat) -> String {
+    match chat.username() {
+        Some(username) => format!("@{username}"),
+        None => chat.name().into(),
+    }
+}
+
+/// Cut `text` to at most `max` characters, marking the cut with an ellipsis.
+fn truncate(text: &str, max: usize) -> String {
+    match text.char_indices().nth(max.saturating_sub(1)) {
+        Some((i, _)) if text[i..].chars().count() > 1 => format!("{}…", &text[..i]),
+        _ => text.into(),
+    }
+}
+
+fn message_source(message: &Message, message_id: Option<i32>, options: &str) -> Source {
+    Source {
+        message_id,
+        sender: message.sender().as_ref().map(chat_name),
+        sender_id: message.sender().map(|x| x.id()),
+        command: message.text().into(),
+        options: options.into(),
+    }
+}
+
 trait RunCommand {
     async fn help(&mut self, message: &Message) -> Result<(), Error>;
-    async fn set_avatar(&mut self, message: &Message, opt: &Opt) -> Result<(), Error>;
+    async fn set_avatar(&mut self, message: &Message, args: &str) -> Result<(), Error>;
+    async fn schedule(&mut self, message: &Message, args: &str) -> Result<(), Error>;
+    async fn pool(&mut self, message: &Message, args: &str) -> Result<(), Error>;
+    async fn vote(&mut self, message: &Message, args: &str) -> Result<(), Error>;
+    async fn callback(&mut self, query: &CallbackQuery) -> Result<(), Error>;
+    async fn submit_avatar(
+        &mut self,
+        message: &Message,
+        chat_last_update: ChatLock,
+        avatar: Avatar,
+        source: Source,
+        restore_time: Option<DateTime<Utc>>,
+        needs_approval: bool,
+    ) -> Result<(), Error>;
+    async fn start_review(
+        &mut self,
+        message: &Message,
+        chat_last_update: ChatLock,
+        avatar: Avatar,
+        source: Source,
+        restore_time: Option<DateTime<Utc>>,
+        review: Review,
+    ) -> Result<(), Error>;
+    async fn check_policy(&mut self, chat: &Chat, user: Option<Chat>) -> Result<bool, Error>;
+    async fn policy(&mut self, message: &Message, args: &str) -> Result<(), Error>;
+    async fn settings(&mut self, message: &Message, args: &str) -> Result<(), Error>;
+    async fn preset(&mut self, message: &Message, args: &str) -> Result<(), Error>;
+    async fn allow_chat(&mut self, message: &Message, args: &str, allow: bool)
+        -> Result<(), Error>;
+    async fn chats(&mut self, message: &Message) -> Result<(), Error>;
+    async fn is_admin(&mut self, chat: &Chat, user: &Chat) -> Result<bool, Error>;
+    async fn is_sender_admin(&mut self, message: &Message) -> Result<bool, Error>;
+    async fn member_count(&mut self, chat: &Chat) -> Result<usize, Error>;
+    async fn remove_avatar(&mut self, message: &Message) -> Result<(), Error>;
+    async fn get_avatar(&mut self, message: &Message) -> Result<(), Error>;
+    async fn history(&mut self, message: &Message) -> Result<(), Error>;
+    async fn revert(&mut self, message: &Message, args: &str) -> Result<(), Error>;
     async fn set_typing<C: Into<PackedChat>>(&mut self, chat: C) -> Result<(), Error>;
+    async fn process_avatar(&mut self, media_message: &Message, opt: &Opt)
+        -> Result<Avatar, Error>;
     async fn upload_file(&mut self, file: Vec<u8>, name: &str) -> Result<Uploaded, Error>;
+    async fn upload_avatar(&mut self, avatar: &mut Avatar) -> Result<Uploaded, Error>;
+    async fn download_location(
+        &mut self,
+        location: InputFileLocation,
+        dc_id: i32,
+    ) -> Result<Vec<u8>, Error>;
+    async fn full_chat<C: Into<PackedChat>>(&mut self, chat: C) -> Result<enums::ChatFull, Error>;
+    async fn download_avatar<C: Into<PackedChat>>(
+        &mut self,
+        chat: C,
+    ) -> Result<Option<Avatar>, Error>;
     async fn edit_photo<C: Into<PackedChat>>(
         &mut self,
         chat: C,
-        uploaded: Uploaded,
-        is_video: bool,
+        photo: InputChatPhoto,
+    ) -> Result<(), Error>;
+    async fn apply_avatar<C: Into<PackedChat>>(
+        &mut self,
+        chat: C,
+        avatar: Avatar,
+        source: Source,
+        restore_time: Option<DateTime<Utc>>,
+    ) -> Result<(), Error>;
+    async fn run_job(&mut self, job: &Job) -> Result<(), Error>;
+    async fn rotate_pool(&mut self, chat: PackedChat, item: &Item) -> Result<(), Error>;
+    async fn set_avatar_from(
+        &mut self,
+        chat: PackedChat,
+        options: &str,
+        source: Source,
     ) -> Result<(), Error>;
 }
 
@@ -199,16 +381,96 @@ impl RunCommand for Client {
 /help
 显示帮助信息
 
+/get_avatar
+以文件形式回复当前的群头像, 视频头像会同时回复视频和封面
+
+/remove_avatar
+移除群头像
+
+/history
+列出本 bot 最近设置过的群头像及其缩略图, 序号 0 为最近一次设置的头像
+
+/revert [n]
+重新设置 /history 中序号为 n 的头像, 默认为 1, 即上一个头像
+
+/schedule <时间> [选项]
+定时设置群头像, 使用时需要回复包含头像的消息, 时间格式为 YYYY-MM-DD[THH:MM] 或 HH:MM, 选项与 /set_avatar 相同, 仅管理员可用
+
+/schedule list
+列出本群组的定时任务
+
+/schedule cancel <序号>
+取消定时任务, 仅管理员可用
+
+/pool add [选项]
+将回复的消息加入本群组的头像池, 选项与 /set_avatar 相同
+
+/pool list
+列出头像池及轮换设置, 除此之外的 /pool 命令仅管理员可用
+
+/pool remove <序号>
+从头像池移除头像
+
+/pool every <间隔|cron 表达式>
+开启头像轮换, 间隔单位为秒, 或带 d/h/m/s 后缀; cron 表达式为五段式, 如 0 0 * * *; 两次轮换最短间隔 5 分钟
+
+/pool order <next|random>
+设置轮换顺序, 默认按顺序轮换
+
+/pool stop
+停止头像轮换
+
+/policy [admins|anyone|approval]
+查看或设置谁可以修改群头像, 仅管理员可修改: admins 仅管理员, anyone 所有人 (默认), approval 所有人, 但非管理员的 /set_avatar 需要管理员批准
+
+/vote [票数|百分比 [时长]|off]
+查看或设置投票模式, 仅管理员可修改。开启后 /set_avatar 和 /revert 会先发出预览并发起投票, 且不能使用 /remove_avatar, 在时长内 (默认 10 分钟) 达到票数或成员百分比的同意后才会设置头像, 反对票达到票数或同意票已无法达到票数时提前结束, 管理员可以随时取消投票
+
+/settings [名称 值|default]
+查看或设置本群组的默认值, 仅管理员可修改, 值为 default 时恢复默认:
+    cooldown  两次修改群头像的最短间隔, 单位为秒, 或带 d/h/m/s 后缀, 默认及最短 30 秒
+    color     /set_avatar 的默认背景颜色
+    align     /set_avatar 的默认截取位置, 如 t/top
+    detect    on/off, 开启或关闭人脸检测, 关闭时默认截取中间
+    policy    谁可以修改群头像, 同 /policy
+投票模式请使用 /vote 设置
+
+/preset save <名称> <选项>
+保存一组 /set_avatar 选项为预设, 之后可以用 @名称 代替这些选项, 如 /set_avatar @pink
+
+/preset list
+列出本群组的预设, 除此之外的 /preset 命令仅管理员可用
+
+/preset delete <名称>
+删除预设
+
+/allow [群组 id|@用户名]
+/deny [群组 id|@用户名]
+开始或停止向指定群组提供服务, 默认为当前群组, 仅 bot 的所有者可用
+
+/chats
+列出正在提供服务的群组, 仅 bot 的所有者可用
+
 /set_avatar
 设置群头像, 使用时需要回复包含头像的消息, 支持图片、视频、贴纸、文件、链接等, 默认自动检测人脸并截取为头像图片。
 
-可接如下选项, 最多接受三个选项, 选项顺序不敏感:
-    t/top     截取顶部
-    b/bottom  截取底部
+可接如下选项, 选项数量不限, 顺序不敏感, 也可以使用 @名称 引用预设:
+    t/top     截取顶部, 用于竖图
+    b/bottom  截取底部, 用于竖图
+    l/left    截取左侧, 用于横图
+    r/right   截取右侧, 用于横图
     c/center  截取中间, 默认值, 但是自动检测到人脸除外, 可以指定这个选项跳过人脸检测
-    d/dry     回复处理后的头像, 不执行设置头像的操作
+    d/dry     回复处理后的头像, 不执行设置头像的操作, 10 分钟内可以点击回复中的 "应用" 按钮直接设置
     s/show    回复人脸检测结果, 不执行设置头像的操作, 设置这个选项则截取选项和背景颜色都无效
-    color     背景颜色, 默认为白色, 十六进制 RGB 格式或别名, 只对有透明度的头像有效
+    color     背景颜色, 默认为白色, 3 或 6 位十六进制 RGB 格式或别名, 只对有透明度的头像有效, 也可以写作 color=
+    align=    截取位置, 同 t/b/l/r/c
+    start=    视频截取的开始时间, 单位为秒或 [时:]分:秒, 别名 from=
+    end=      视频截取的结束时间, 最长截取 10 秒
+    len=      视频截取的时长, 与 end= 同时指定时以 end= 为准
+    cover=    视频头像的静态封面所在的时间, 从截取后的视频开始计算, 配合 d/dry 时额外回复封面
+    frame=    从视频或动态贴纸中取一帧作为静态头像, 整数为帧序号, 带 s 后缀或小数、冒号为时间, 超出末尾时取最后一帧
+    until=    临时头像, 到指定时间后自动恢复之前的头像, 格式为 YYYY-MM-DD[THH:MM] 或 HH:MM
+    for=      临时头像, 经过指定时长后自动恢复之前的头像, 单位为秒, 或带 d/h/m/s 后缀
 当前可用背景颜色别名:
     tr/trans  跨性别旗
 
@@ -218,6 +480,10 @@ impl RunCommand for Client {
     /set_avatar tr d
     /set_avatar t ffc0cb
     /set_avatar t ffc0cb d
+    /set_avatar start=1:05 len=8
+    /set_avatar frame=2.5s
+    /set_avatar for=24h
+    /set_avatar @pink d
 "###
         .trim();
 
@@ -249,33 +515,121 @@ impl RunCommand for Client {
     async fn edit_photo<C: Into<PackedChat>>(
         &mut self,
         chat: C,
-        uploaded: Uploaded,
-        is_video: bool,
+        photo: InputChatPhoto,
     ) -> Result<(), Error> {
         let chat = Into::<PackedChat>::into(chat);
-        let channel = chat
-            .try_to_input_channel()
-            .ok_or("获取群组信息失败".error())?;
-
-        let mut photo = InputChatUploadedPhoto {
-            file: None,
-            video: None,
-            video_start_ts: None,
-            video_emoji_markup: None,
-        };
-        let input_file = uploaded.into();
-        if is_video {
-            photo.video.replace(input_file);
+
+        // TODO
+        if let Some(channel) = chat.try_to_input_channel() {
+            let _ = self.invoke(&EditPhoto { photo, channel }).await?;
+        } else if matches!(chat.ty, PackedType::Chat) {
+            let chat_id = chat.id;
+            let _ = self.invoke(&EditChatPhoto { chat_id, photo }).await?;
         } else {
-            photo.file.replace(input_file);
+            return "获取群组信息失败".result();
         }
-        let photo = InputChatPhoto::InputChatUploadedPhoto(photo);
+        Ok(())
+    }
 
-        // TODO
-        let _ = self.invoke(&EditPhoto { photo, channel }).await?;
+    async fn apply_avatar<C: Into<PackedChat>>(
+        &mut self,
+        chat: C,
+        mut avatar: Avatar,
+        source: Source,
+        restore_time: Option<DateTime<Utc>>,
+    ) -> Result<(), Error> {
+        let chat = Into::<PackedChat>::into(chat);
+        let previous = if restore_time.is_some() && !schedule::has_restore(chat.id) {
+            Some(self.download_avatar(chat).await?)
+        } else {
+            None
+        };
+
+        let uploaded = self.upload_avatar(&mut avatar).await?;
+        let photo = uploaded_photo(uploaded, avatar.is_video, avatar.video_start_ts);
+        self.edit_photo(chat, photo).await?;
+
+        if let Err(e) = history::record(chat.id, &avatar, source) {
+            println!("Failed to record avatar history: {e}");
+        }
+
+        match (restore_time, previous) {
+            (Some(time), Some(previous)) => {
+                let avatar = previous
+                    .map(|x| AvatarFile::save(&format!("restore/{}", chat.id), &x))
+                    .transpose()?;
+                schedule::add(chat, time, Action::Restore { avatar })?;
+            }
+            (Some(time), None) => schedule::reschedule_restore(chat.id, time)?,
+            (None, _) => schedule::cancel_restore(chat.id)?,
+        }
+        Ok(())
+    }
+
+    async fn run_job(&mut self, job: &Job) -> Result<(), Error> {
+        let chat = job.chat()?;
+        let mut chat_last_update = lock_chat(job.chat_id).await?;
+
+        match &job.action {
+            Action::Restore { avatar: Some(x) } => {
+                let source = Source {
+                    message_id: None,
+                    sender: None,
+                    sender_id: None,
+                    command: "临时头像到期自动恢复".into(),
+                    options: String::new(),
+                };
+                self.apply_avatar(chat, x.load()?, source, None).await?;
+            }
+            Action::Restore { avatar: None } => {
+                self.edit_photo(chat, InputChatPhoto::Empty).await?;
+            }
+            Action::SetAvatar { options, source } => {
+                self.set_avatar_from(chat, options, source.clone()).await?;
+            }
+        }
+        chat_last_update.touch();
+        Ok(())
+    }
+
+    async fn rotate_pool(&mut self, chat: PackedChat, item: &Item) -> Result<(), Error> {
+        let mut chat_last_update = lock_chat(chat.id).await?;
+        self.set_avatar_from(chat, &item.options, item.source.clone())
+            .await?;
+        chat_last_update.touch();
         Ok(())
     }
 
+    async fn set_avatar_from(
+        &mut self,
+        chat: PackedChat,
+        options: &str,
+        source: Source,
+    ) -> Result<(), Error> {
+        let opt = Opt::parse(options, &settings::get(chat.id))?;
+        let message_id = source.message_id.ok_or("Invalid avatar source")?;
+        let media_message = self
+            .get_messages_by_id(chat, &[message_id])
+            .await?
+            .swap_remove(0)
+            .ok_or("读取头像所在的消息失败".error())?;
+        let avatar = self.process_avatar(&media_message, &opt).await?;
+        let restore_time = opt.expire.map(|x| x.time()).filter(|x| *x > Utc::now());
+        self.apply_avatar(chat, avatar, source, restore_time).await
+    }
+
+    async fn upload_avatar(&mut self, avatar: &mut Avatar) -> Result<Uploaded, Error> {
+        if let Some(x) = &avatar.uploaded {
+            return Ok(x.clone());
+        }
+
+        let uploaded = self
+            .upload_file(avatar.data.clone(), &avatar.file_name())
+            .await?;
+        avatar.uploaded = Some(uploaded.clone());
+        Ok(uploaded)
+    }
+
     async fn upload_file(&mut self, file: Vec<u8>, name: &str) -> Result<Uploaded, Error> {
         let len = file.len();
         let mut file = Cursor::new(file);
@@ -283,174 +637,1335 @@ impl RunCommand for Client {
         Ok(uploaded)
     }
 
-    async fn set_avatar(&mut self, message: &Message, opt: &Opt) -> Result<(), Error> {
+    async fn download_location(
+        &mut self,
+        location: InputFileLocation,
+        dc_id: i32,
+    ) -> Result<Vec<u8>, Error> {
+        let mut data = Vec::new();
+        loop {
+            let get_file = GetFile {
+                precise: false,
+                cdn_supported: false,
+                location: location.clone(),
+                offset: data.len() as _,
+                limit: DOWNLOAD_CHUNK_SIZE,
+            };
+            match self.invoke_in_dc(&get_file, dc_id).await? {
+                enums::upload::File::File(x) => {
+                    let finished = x.bytes.len() < DOWNLOAD_CHUNK_SIZE as usize;
+                    data.extend(x.bytes);
+                    if finished {
+                        break;
+                    }
+                }
+                enums::upload::File::CdnRedirect(_) => return "不支持从 CDN 下载文件".result(),
+            }
+        }
+
+        Ok(data)
+    }
+
+    async fn full_chat<C: Into<PackedChat>>(&mut self, chat: C) -> Result<enums::ChatFull, Error> {
+        let chat = Into::<PackedChat>::into(chat);
+
+        let full_chat = if let Some(channel) = chat.try_to_input_channel() {
+            self.invoke(&GetFullChannel { channel }).await?
+        } else if matches!(chat.ty, PackedType::Chat) {
+            self.invoke(&GetFullChat { chat_id: chat.id }).await?
+        } else {
+            return "获取群组信息失败".result();
+        };
+        let enums::messages::ChatFull::Full(full_chat) = full_chat;
+        Ok(full_chat.full_chat)
+    }
+
+    async fn member_count(&mut self, chat: &Chat) -> Result<usize, Error> {
+        let count = match self.full_chat(chat).await? {
+            enums::ChatFull::Full(x) => match x.participants {
+                enums::ChatParticipants::Participants(x) => x.participants.len(),
+                enums::ChatParticipants::Forbidden(_) => 0,
+            },
+            enums::ChatFull::ChannelFull(x) => x.participants_count.unwrap_or_default() as _,
+        };
+
+        Ok(count)
+    }
+
+    async fn is_admin(&mut self, chat: &Chat, user: &Chat) -> Result<bool, Error> {
+        // anonymous admins send messages as the chat itself
+        if user.id() == chat.id() {
+            return Ok(true);
+        }
+
+        let permissions = self.get_permissions(chat, user).await?;
+        Ok(permissions.is_admin() || permissions.is_creator())
+    }
+
+    async fn is_sender_admin(&mut self, message: &Message) -> Result<bool, Error> {
+        match message.sender() {
+            Some(x) => self.is_admin(&message.chat(), &x).await,
+            None => Ok(false),
+        }
+    }
+
+    async fn download_avatar<C: Into<PackedChat>>(
+        &mut self,
+        chat: C,
+    ) -> Result<Option<Avatar>, Error> {
+        let photo = match self.full_chat(chat).await? {
+            enums::ChatFull::Full(x) => x.chat_photo,
+            enums::ChatFull::ChannelFull(x) => Some(x.chat_photo),
+        };
+        let photo = match photo {
+            Some(enums::Photo::Photo(x)) => x,
+            _ => return Ok(None),
+        };
+
+        let video = photo
+            .video_sizes
+            .iter()
+            .flatten()
+            .filter_map(|x| match x {
+                enums::VideoSize::Size(x) => Some(x),
+                _ => None,
+            })
+            .max_by_key(|x| x.w * x.h);
+        let (thumb_size, is_video, video_start_ts) = match video {
+            Some(x) => (x.r#type.clone(), true, x.video_start_ts),
+            None => {
+                let (_, thumb_size) = photo
+                    .sizes
+                    .iter()
+                    .filter_map(|x| match x {
+                        enums::PhotoSize::Size(x) => Some((x.w * x.h, &x.r#type)),
+                        enums::PhotoSize::Progressive(x) => Some((x.w * x.h, &x.r#type)),
+                        _ => None,
+                    })
+                    .max_by_key(|x| x.0)
+                    .ok_or("读取群头像失败".error())?;
+                (thumb_size.clone(), false, None)
+            }
+        };
+
+        let location = InputFileLocation::InputPhotoFileLocation(InputPhotoFileLocation {
+            id: photo.id,
+            access_hash: photo.access_hash,
+            file_reference: photo.file_reference,
+            thumb_size,
+        });
+        let data = self.download_location(location, photo.dc_id).await?;
+
+        Ok(Some(Avatar {
+            data,
+            is_video,
+            video_start_ts,
+            uploaded: None,
+        }))
+    }
+
+    async fn get_avatar(&mut self, message: &Message) -> Result<(), Error> {
         let chat = &message.chat();
         let chat_id = chat.id();
+        if !chats::is_allowed(chat_id) {
+            return format!("尚未向本群组 ({chat_id}) 提供服务").result();
+        }
 
-        let mut chat_last_update = if let Some(x) = LAST_UPDATE.get(&chat_id) {
-            x.try_lock().or("正在处理之前的请求, 请稍后...".result())?
+        let avatar = self
+            .download_avatar(chat)
+            .await?
+            .ok_or("本群组尚未设置头像".error())?;
+
+        let mut files = Vec::new();
+        if avatar.is_video {
+            let frame = Frame::Time(avatar.video_start_ts.unwrap_or(0.0));
+            let cover = video_to_png(avatar.data.clone(), frame)?;
+            files.push((avatar.data, "avatar.mp4", "video/mp4"));
+            files.push((cover, "cover.png", "image/png"));
         } else {
+            files.push((avatar.data, "avatar.jpg", "image/jpeg"));
+        }
+
+        for (data, name, mime_type) in files {
+            let uploaded = self.upload_file(data, name).await?;
+            let input_message = InputMessage::default()
+                .reply_to(Some(message.id()))
+                .document(uploaded)
+                .mime_type(mime_type);
+            self.send_message(chat, input_message).await?;
+        }
+        Ok(())
+    }
+
+    async fn history(&mut self, message: &Message) -> Result<(), Error> {
+        let chat = &message.chat();
+        let chat_id = chat.id();
+        if !chats::is_allowed(chat_id) {
             return format!("尚未向本群组 ({chat_id}) 提供服务").result();
+        }
+
+        let entries = history::entries(chat_id);
+        if entries.is_empty() {
+            return "暂无头像历史记录".result();
+        }
+
+        let mut text = String::new();
+        for (i, x) in entries.iter().enumerate() {
+            let time = x.time.with_timezone(&Local).format(TIME_FORMAT);
+            let kind = if x.avatar.is_video {
+                "视频"
+            } else {
+                "图片"
+            };
+            let sender = x.source.sender.as_deref().unwrap_or("未知用户");
+            let command = match x.source.options.as_str() {
+                "" => truncate(&x.source.command, MAX_COMMAND_LENGTH),
+                x => format!("/set_avatar {}", truncate(x, MAX_COMMAND_LENGTH)),
+            };
+            text.push_str(&format!("{i}. {time} {kind} {sender}: {command}\n"));
+        }
+        let text = text.trim_end();
+
+        let thumbnails = history::thumbnails(&entries)?;
+        let uploaded = self.upload_file(thumbnails, "history.png").await?;
+        if text.chars().count() <= MAX_CAPTION_LENGTH {
+            let input_message = InputMessage::text(text)
+                .reply_to(Some(message.id()))
+                .photo(uploaded);
+            self.send_message(chat, input_message).await?;
+        } else {
+            let input_message = InputMessage::default()
+                .reply_to(Some(message.id()))
+                .photo(uploaded);
+            let photo = self.send_message(chat, input_message).await?;
+            let input_message = InputMessage::text(text).reply_to(Some(photo.id()));
+            self.send_message(chat, input_message).await?;
+        }
+        Ok(())
+    }
+
+    async fn revert(&mut self, message: &Message, args: &str) -> Result<(), Error> {
+        let chat = &message.chat();
+        let n = match args {
+            "" => 1,
+            x => usize::from_str(x).or("序号格式错误, 请使用 /history 中的序号".result())?,
         };
-        if !opt.dry_run && chat_last_update.elapsed() < MIN_INTERVAL {
+        if self.check_policy(chat, message.sender()).await? {
+            return "非管理员只能通过 /set_avatar 申请修改群头像".result();
+        }
+        let chat_last_update = try_lock_chat(chat.id())?;
+        if chat_last_update.elapsed() < cooldown(chat.id()) {
             return "技能冷却中".result();
         }
 
-        if let Some(ref x) = message.reply_to_message_id() {
-            let notify = Arc::new(Notify::new());
-            spawn({
-                let mut bot = self.clone();
-                let chat = chat.clone();
-                let notify = notify.clone();
-                let mut interval = interval(Duration::from_secs(8));
-                async move {
-                    loop {
-                        select! {
-                            _ = notify.notified() => break,
-                            _ = interval.tick() => bot.set_typing(&chat).await?,
-                        }
-                    }
-                    Ok::<_, Error>(())
-                }
-            });
-            struct Notified(Arc<Notify>);
-            impl Drop for Notified {
-                fn drop(&mut self) {
-                    self.0.notify_one();
-                }
-            }
-            let notify = Notified(notify);
-
-            let media_message = self
-                .get_messages_by_id(chat, &[*x])
-                .await?
-                .swap_remove(0)
-                .ok_or("读取回复的消息失败".error())?;
-
-            let mut file = None;
-            let mut is_square = false;
-            let mut is_video = false;
-            let mut error = None;
-            if let Some(media) = media_message.media() {
-                let mut download = None;
-                let mut mime = None;
-                let mut sticker_id = 0;
-                match &media {
-                    Media::Photo(x) => {
-                        if let Some(x) = x.thumbs().largest() {
-                            download.replace(x.size() <= MAX_FILESIZE);
-                        }
-                    }
-                    Media::Document(x) => {
-                        mime = x.mime_type();
-                        match mime.and_then(|x| x.split_once('/').map(|x| x.0)) {
-                            Some("video" | "image") => {
-                                download.replace(x.size() <= MAX_FILESIZE as _);
-                                if let Some((width, height)) = x.resolution() {
-                                    is_square = width == height;
-                                }
-                            }
-                            _ => error = Some("不支持的文件类型"),
-                        };
-                    }
-                    Media::Sticker(x) => {
-                        download.replace(x.document.size() <= MAX_FILESIZE as _);
-                        mime = x.document.mime_type();
-                        if let Some((width, height)) = x.document.resolution() {
-                            is_square = width == height;
-                        }
-                        sticker_id = x.document.id();
-                    }
-                    _ => {}
+        let Some(entry) = history::entries(chat.id()).into_iter().nth(n) else {
+            return format!("没有序号为 {n} 的历史头像").result();
+        };
+        let source = message_source(message, None, &entry.source.options);
+        self.submit_avatar(
+            message,
+            chat_last_update,
+            entry.avatar.load()?,
+            source,
+            None,
+            false,
+        )
+        .await
+    }
+
+    async fn schedule(&mut self, message: &Message, args: &str) -> Result<(), Error> {
+        let chat = &message.chat();
+        let chat_id = chat.id();
+        if !chats::is_allowed(chat_id) {
+            return format!("尚未向本群组 ({chat_id}) 提供服务").result();
+        }
+
+        let (subcommand, args) = args.split_once(char::is_whitespace).unwrap_or((args, ""));
+        let args = args.trim();
+        if subcommand != "list" && !self.is_sender_admin(message).await? {
+            return "只有管理员可以管理定时任务".result();
+        }
+        let text = match subcommand {
+            "list" => {
+                let jobs = schedule::jobs(chat_id);
+                if jobs.is_empty() {
+                    return "暂无定时任务".result();
                 }
 
-                let mime = mime.map(str::to_string);
-                if let Some(download) = download {
-                    if download {
-                        let mut buf = Vec::new();
-                        let mut downloader = self.iter_download(&Downloadable::Media(media));
-                        while let Some(x) = downloader.next().await? {
-                            buf.extend(x);
+                let mut text = String::new();
+                for x in jobs {
+                    let time = x.time.with_timezone(&Local).format(TIME_FORMAT);
+                    let line = match x.action {
+                        Action::Restore { .. } => {
+                            format!("{}. {time} 恢复临时头像之前的头像", x.id)
                         }
-
-                        if let Some(x) = mime {
-                            if x.starts_with("video/") {
-                                is_video = true;
-                                if !is_square || !x.starts_with("video/mp4") {
-                                    buf = video_to_mp4(buf, opt.color)?;
-                                    is_square = true
-                                }
-                            } else if x == "application/x-tgsticker" {
-                                is_video = true;
-                                if is_square {
-                                    buf = tgs_to_mp4(buf, &format!("{sticker_id}"), opt.color)?;
-                                } else {
-                                    buf = tgs_to_png(buf, &format!("{sticker_id}"))?;
-                                }
-                            }
+                        Action::SetAvatar { options, source } => {
+                            let sender = source.sender.as_deref().unwrap_or("未知用户");
+                            format!("{}. {time} {sender} 设置头像: {options}", x.id)
                         }
-                        file = Some(buf);
-                    } else {
-                        error = Some("文件大小超出限制");
-                    }
+                    };
+                    text.push_str(&line);
+                    text.push('\n');
                 }
-            };
-
-            if file.is_none() {
-                if let Some(x) = media_message.url() {
-                    error = None;
-                    file = link_to_img(x).await?
+                text
+            }
+            "cancel" => {
+                let id: u64 = args.parse().or("请指定要取消的定时任务序号".result())?;
+                if !schedule::cancel(chat_id, id)? {
+                    return format!("没有序号为 {id} 的定时任务").result();
                 }
+                format!("已取消定时任务 {id}")
             }
+            datetime => {
+                let time = parse_datetime(datetime)
+                    .ok_or("时间格式错误, 请使用 YYYY-MM-DD[THH:MM] 或 HH:MM".error())?
+                    .with_timezone(&Utc);
+                if time <= Utc::now() {
+                    return "定时时间必须晚于当前时间".result();
+                }
+                let reply_to = message
+                    .reply_to_message_id()
+                    .ok_or("使用 schedule 命令时请回复包含头像的消息".error())?;
+                // presets may change before the job runs, so store what they expand to now
+                let settings = settings::get(chat_id);
+                let options = expand_presets(args, &settings)?;
+                if Opt::parse(&options, &settings)?.dry_run {
+                    return "定时任务不支持 d/dry 和 s/show 选项".result();
+                }
 
-            if let Some(mut buf) = file {
-                is_video = is_video && is_square;
-                let file_name = if is_video {
-                    "file.mp4"
-                } else {
-                    image_to_png(&mut buf, opt)?;
-                    "file.png"
+                let action = Action::SetAvatar {
+                    source: message_source(message, Some(reply_to), &options),
+                    options,
                 };
-                let uploaded = self.upload_file(buf, file_name).await?;
+                let id = schedule::add(chat.pack(), time, action)?;
+                let time = time.with_timezone(&Local).format(TIME_FORMAT);
+                format!("已添加定时任务 {id}, 将于 {time} 执行")
+            }
+        };
 
-                drop(notify);
+        let input_message = InputMessage::text(text.trim_end()).reply_to(Some(message.id()));
+        self.send_message(chat, input_message).await?;
+        Ok(())
+    }
 
-                if opt.dry_run {
-                    let mut input_message = InputMessage::default().reply_to(Some(message.id()));
-                    if is_video {
-                        input_message = input_message.document(uploaded).mime_type("video/mp4");
-                    } else {
-                        input_message = input_message.photo(uploaded);
-                    }
-                    self.send_message(chat, input_message).await?;
-                } else {
-                    self.edit_photo(chat, uploaded, is_video).await?;
-                    *chat_last_update = Instant::now();
+    async fn pool(&mut self, message: &Message, args: &str) -> Result<(), Error> {
+        let chat = &message.chat();
+        let chat_id = chat.id();
+        if !chats::is_allowed(chat_id) {
+            return format!("尚未向本群组 ({chat_id}) 提供服务").result();
+        }
+
+        let (subcommand, args) = args.split_once(char::is_whitespace).unwrap_or((args, ""));
+        let args = args.trim();
+        if subcommand != "list" && !self.is_sender_admin(message).await? {
+            return "只有管理员可以管理头像池".result();
+        }
+        let text = match subcommand {
+            "add" => {
+                let reply_to = message
+                    .reply_to_message_id()
+                    .ok_or("使用 pool add 命令时请回复包含头像的消息".error())?;
+                let settings = settings::get(chat_id);
+                let options = expand_presets(args, &settings)?;
+                if Opt::parse(&options, &settings)?.dry_run {
+                    return "头像池不支持 d/dry 和 s/show 选项".result();
                 }
-            } else {
-                return error.unwrap_or("未检测到受支持的头像").result();
+
+                let item = Item {
+                    source: message_source(message, Some(reply_to), &options),
+                    options,
+                };
+                let count = pool::add(chat.pack(), item)?;
+                format!("已加入头像池, 当前共 {count} 个头像")
             }
-        } else {
-            return "使用 set_avatar 命令时请回复包含头像的消息 (照片、视频、贴纸、文件)".result();
-        }
-        Ok(())
-    }
-}
+            "list" => {
+                let pool = pool::get(chat_id);
+                if pool.items.is_empty() {
+                    return "头像池为空".result();
+                }
 
-pub fn handle_update(client: &Client, update: Update) {
-    let username = USERNAME.get().unwrap();
-    match update {
-        Update::NewMessage(message) if !message.outgoing() => {
-            if let Some(command) = message.bot_command(username) {
-                let mut bot = client.clone();
+                let mut text = String::new();
+                for (i, x) in pool.items.iter().enumerate() {
+                    let sender = x.source.sender.as_deref().unwrap_or("未知用户");
+                    text.push_str(&format!("{}. {sender}: {}\n", i + 1, x.options));
+                }
+                match (&pool.rotation, pool.next_time) {
+                    (Some(rotation), Some(time)) => {
+                        let order = if pool.random { "随机" } else { "顺序" };
+                        let time = time.with_timezone(&Local).format(TIME_FORMAT);
+                        text.push_str(&format!("\n轮换: {rotation}, {order}, 下次轮换 {time}"));
+                    }
+                    _ => text.push_str("\n轮换: 未开启"),
+                }
+                text
+            }
+            "remove" => {
+                let n: usize = args.parse().or("请指定要移除的头像序号".result())?;
+                let removed = match n.checked_sub(1) {
+                    Some(x) => pool::remove(chat.pack(), x)?,
+                    None => None,
+                };
+                if removed.is_none() {
+                    return format!("头像池中没有序号为 {n} 的头像").result();
+                }
+                format!("已从头像池移除 {n}")
+            }
+            "every" => {
+                if pool::min_interval(args)
+                    .is_some_and(|x| x.num_seconds() < MIN_ROTATION_INTERVAL.as_secs() as _)
+                {
+                    return "轮换间隔不能短于 5 分钟".result();
+                }
+                if pool::next_rotation(args).is_none() {
+                    return "请指定轮换间隔 (如 12h、1d) 或 cron 表达式 (如 0 0 * * *)".result();
+                }
+                let time = pool::set_rotation(chat.pack(), Some(args.into()))?
+                    .ok_or("Invalid rotation")?;
+                let time = time.with_timezone(&Local).format(TIME_FORMAT);
+                format!("已开启头像轮换, 下次轮换 {time}")
+            }
+            "order" => {
+                let random = match args {
+                    "next" => false,
+                    "random" => true,
+                    _ => return "请指定轮换顺序: next 或 random".result(),
+                };
+                pool::set_random(chat.pack(), random)?;
+                "已设置轮换顺序".into()
+            }
+            "stop" => {
+                pool::set_rotation(chat.pack(), None)?;
+                "已停止头像轮换".into()
+            }
+            _ => {
+                return "未知的子命令, 可用的子命令为 add、list、remove、every、order、stop"
+                    .result()
+            }
+        };
+
+        let input_message = InputMessage::text(text.trim_end()).reply_to(Some(message.id()));
+        self.send_message(chat, input_message).await?;
+        Ok(())
+    }
+
+    async fn vote(&mut self, message: &Message, args: &str) -> Result<(), Error> {
+        let chat = &message.chat();
+        let chat_id = chat.id();
+        if !chats::is_allowed(chat_id) {
+            return format!("尚未向本群组 ({chat_id}) 提供服务").result();
+        }
+
+        let text = if args.is_empty() {
+            match settings::get(chat_id).vote {
+                Some(x) => format!(
+                    "投票模式: {} 同意后生效, 投票时长 {} 秒",
+                    x.threshold, x.window
+                ),
+                None => "投票模式: 未开启".into(),
+            }
+        } else {
+            if !self.is_sender_admin(message).await? {
+                return "只有管理员可以修改投票模式".result();
+            }
+
+            if args == "off" {
+                settings::update(chat_id, |x| x.vote = None)?;
+                "已关闭投票模式".into()
+            } else {
+                let (threshold, window) =
+                    args.split_once(char::is_whitespace).unwrap_or((args, ""));
+                let threshold = parse_threshold(threshold)
+                    .ok_or("票数格式错误, 请使用正整数或百分比, 如 5 或 10%".error())?;
+                let window = match window.trim() {
+                    "" => DEFAULT_VOTE_WINDOW,
+                    x => parse_duration(x)
+                        .and_then(|x| x.to_std().ok())
+                        .filter(|x| !x.is_zero() && *x <= MAX_VOTE_WINDOW)
+                        .ok_or("投票时长格式错误, 最长为 1 天".error())?,
+                };
+
+                let vote = Vote {
+                    threshold,
+                    window: window.as_secs(),
+                };
+                settings::update(chat_id, |x| x.vote = Some(vote))?;
+                format!(
+                    "已开启投票模式: {threshold} 同意后生效, 投票时长 {} 秒",
+                    vote.window
+                )
+            }
+        };
+
+        let input_message = InputMessage::text(text).reply_to(Some(message.id()));
+        self.send_message(chat, input_message).await?;
+        Ok(())
+    }
+
+    /// Apply the avatar, or put it up for review first if the chat requires so.
+    async fn submit_avatar(
+        &mut self,
+        message: &Message,
+        mut chat_last_update: ChatLock,
+        avatar: Avatar,
+        source: Source,
+        restore_time: Option<DateTime<Utc>>,
+        needs_approval: bool,
+    ) -> Result<(), Error> {
+        let review = if needs_approval {
+            Some(Review::Approval)
+        } else {
+            settings::get(message.chat().id()).vote.map(Review::Vote)
+        };
+        if let Some(review) = review {
+            return self
+                .start_review(
+                    message,
+                    chat_last_update,
+                    avatar,
+                    source,
+                    restore_time,
+                    review,
+                )
+                .await;
+        }
+
+        self.apply_avatar(message.chat(), avatar, source, restore_time)
+            .await?;
+        chat_last_update.touch();
+        Ok(())
+    }
+
+    async fn start_review(
+        &mut self,
+        message: &Message,
+        chat_last_update: ChatLock,
+        mut avatar: Avatar,
+        source: Source,
+        restore_time: Option<DateTime<Utc>>,
+        review: Review,
+    ) -> Result<(), Error> {
+        let chat = message.chat();
+        let sender = source.sender.as_deref().unwrap_or("未知用户");
+        let (kind, threshold, voters, window, markup) = match review {
+            Review::Vote(vote) => {
+                let member_count = self.member_count(&chat).await?;
+                let threshold = vote.threshold.votes(member_count);
+                // the proposer can't vote, and a count of 0 means it is unknown
+                let voters = member_count.checked_sub(1);
+                if let Some(x) = voters.filter(|x| *x < threshold) {
+                    return format!("需要 {threshold} 票同意, 但本群组只有 {x} 名成员可以投票")
+                        .result();
+                }
+                let window = Duration::from_secs(vote.window);
+                (Kind::Vote, threshold, voters, window, vote_markup(0, 0))
+            }
+            Review::Approval => (Kind::Approval, 1, None, APPROVAL_WINDOW, approval_markup()),
+        };
+        let deadline = (Local::now() + TimeDelta::from_std(window)?).format(TIME_FORMAT);
+        let text = match review {
+            Review::Vote(_) => format!(
+                "{sender} 提议设置新的群头像, {threshold} 票同意后生效, 投票截止时间 {deadline}"
+            ),
+            Review::Approval => {
+                format!("{sender} 申请设置新的群头像, 需要管理员批准, 截止时间 {deadline}")
+            }
+        };
+
+        let uploaded = self.upload_avatar(&mut avatar).await?;
+        let mut input_message = InputMessage::text(text)
+            .reply_to(Some(message.id()))
+            .reply_markup(&markup);
+        if avatar.is_video {
+            input_message = input_message.document(uploaded).mime_type("video/mp4");
+        } else {
+            input_message = input_message.photo(uploaded);
+        }
+        let review_message = self.send_message(&chat, input_message).await?;
+        let decided = pending::insert(
+            chat.id(),
+            review_message.id(),
+            kind,
+            avatar,
+            source,
+            restore_time,
+            threshold,
+            voters,
+        );
+
+        let mut bot = self.clone();
+        spawn(async move {
+            let mut chat_last_update = chat_last_update;
+            let decision = timeout(window, decided).await.ok().and_then(|x| x.ok());
+            let Some(pending) = pending::remove(chat.id(), review_message.id()) else {
+                return;
+            };
+
+            let result = match review {
+                Review::Vote(_) => "投票",
+                Review::Approval => "审批",
+            };
+            let text = match decision {
+                Some(Decision::Approved) => {
+                    let ret = bot
+                        .apply_avatar(&chat, pending.avatar, pending.source, pending.restore_time)
+                        .await;
+                    match ret {
+                        Ok(()) => {
+                            chat_last_update.touch();
+                            format!("{result}通过, 已设置群头像")
+                        }
+                        Err(e) => {
+                            let error = e.message().unwrap_or_else(|| {
+                                println!("Failed to apply reviewed avatar: {e}");
+                                "发生了一些错误"
+                            });
+                            format!("{result}通过, 但设置群头像失败: {error}")
+                        }
+                    }
+                }
+                Some(Decision::Canceled) => format!("{result}已被管理员取消"),
+                _ => format!("{result}未通过"),
+            };
+            if let Err(e) = review_message.edit(InputMessage::text(text)).await {
+                println!("Failed to edit review message: {e}");
+            }
+        });
+        Ok(())
+    }
+
+    async fn callback(&mut self, query: &CallbackQuery) -> Result<(), Error> {
+        let chat = query.chat();
+        let message = query.load_message().await?;
+        match query.data() {
+            x @ (b"vote:yes" | b"vote:no") => {
+                let approve = x == b"vote:yes";
+                let tally = pending::vote(chat.id(), message.id(), query.sender().id(), approve)?;
+                if !tally.decided {
+                    let input_message = InputMessage::text(message.text())
+                        .reply_markup(&vote_markup(tally.approvals, tally.rejections));
+                    // fails with MESSAGE_NOT_MODIFIED when the vote is unchanged
+                    let _ = message.edit(input_message).await;
+                }
+
+                let text = format!(
+                    "已投票, 当前 {}/{} 票同意",
+                    tally.approvals, tally.threshold
+                );
+                query.answer().text(text).send().await?;
+            }
+            x @ (b"approval:yes" | b"approval:no") => {
+                if !self.is_admin(chat, query.sender()).await? {
+                    return "只有管理员可以审批".result();
+                }
+                let decision = match x {
+                    b"approval:yes" => Decision::Approved,
+                    _ => Decision::Rejected,
+                };
+                if !pending::decide(chat.id(), message.id(), Kind::Approval, decision) {
+                    return "该申请已处理".result();
+                }
+                query.answer().text("已处理").send().await?;
+            }
+            b"vote:cancel" => {
+                if !self.is_admin(chat, query.sender()).await? {
+                    return "只有管理员可以取消投票".result();
+                }
+                if !pending::decide(chat.id(), message.id(), Kind::Vote, Decision::Canceled) {
+                    return "投票已结束".result();
+                }
+                query.answer().text("已取消投票").send().await?;
+            }
+            b"apply" => {
+                let sender = query.sender().clone();
+                let needs_approval = self.check_policy(chat, Some(sender.clone())).await?;
+                let chat_last_update = try_lock_chat(chat.id())?;
+                if chat_last_update.elapsed() < cooldown(chat.id()) {
+                    return "技能冷却中".result();
+                }
+
+                let preview = pending::take_preview(chat.id(), message.id())
+                    .ok_or("预览已过期, 请重新执行命令".error())?;
+                // for= counts from now rather than from the dry run
+                let restore_time = preview.expire.map(|x| x.time());
+                if restore_time.is_some_and(|x| x <= Utc::now()) {
+                    return "恢复时间必须晚于当前时间".result();
+                }
+                query.answer().text("已提交").send().await?;
+
+                let source = Source {
+                    sender: Some(chat_name(&sender)),
+                    sender_id: Some(sender.id()),
+                    ..preview.source
+                };
+                let ret = self
+                    .submit_avatar(
+                        &message,
+                        chat_last_update,
+                        preview.avatar,
+                        source,
+                        restore_time,
+                        needs_approval,
+                    )
+                    .await;
+                // the query is answered already, so report errors in the chat instead
+                if let Err(e) = ret {
+                    println!("Failed to apply preview: {e}");
+                    report_failure(self, chat.pack(), "应用预览", e).await;
+                }
+            }
+            _ => return "未知的操作".result(),
+        }
+        Ok(())
+    }
+
+    /// Returns whether the request has to be approved by an admin first.
+    async fn check_policy(&mut self, chat: &Chat, user: Option<Chat>) -> Result<bool, Error> {
+        let policy = settings::get(chat.id()).policy;
+        if matches!(policy, Policy::Anyone) {
+            return Ok(false);
+        }
+
+        let is_admin = match user {
+            Some(x) => self.is_admin(chat, &x).await?,
+            None => false,
+        };
+        match policy {
+            _ if is_admin => Ok(false),
+            Policy::Admins => "本群组只允许管理员修改群头像".result(),
+            _ => Ok(true),
+        }
+    }
+
+    async fn policy(&mut self, message: &Message, args: &str) -> Result<(), Error> {
+        let chat = &message.chat();
+        let chat_id = chat.id();
+        if !chats::is_allowed(chat_id) {
+            return format!("尚未向本群组 ({chat_id}) 提供服务").result();
+        }
+
+        let text = if args.is_empty() {
+            format!("修改群头像权限: {}", settings::get(chat_id).policy)
+        } else {
+            if !self.is_sender_admin(message).await? {
+                return "只有管理员可以修改权限设置".result();
+            }
+
+            let policy = parse_policy(args).ok_or("请指定 admins、anyone 或 approval".error())?;
+            settings::update(chat_id, |x| x.policy = policy)?;
+            format!("已设置修改群头像权限: {policy}")
+        };
+
+        let input_message = InputMessage::text(text).reply_to(Some(message.id()));
+        self.send_message(chat, input_message).await?;
+        Ok(())
+    }
+
+    async fn settings(&mut self, message: &Message, args: &str) -> Result<(), Error> {
+        let chat = &message.chat();
+        let chat_id = chat.id();
+        if !chats::is_allowed(chat_id) {
+            return format!("尚未向本群组 ({chat_id}) 提供服务").result();
+        }
+
+        let text = if args.is_empty() {
+            let x = settings::get(chat_id);
+            let vote = match x.vote {
+                Some(x) => format!("{} 同意后生效, 投票时长 {} 秒", x.threshold, x.window),
+                None => "未开启".into(),
+            };
+            [
+                format!("冷却时间: {} 秒", cooldown(chat_id).as_secs()),
+                format!(
+                    "背景颜色: {}",
+                    x.color.map_or("默认".into(), |x| x.to_string())
+                ),
+                format!(
+                    "截取位置: {}",
+                    x.align.map_or("默认".into(), |x| x.to_string())
+                ),
+                format!(
+                    "人脸检测: {}",
+                    if x.skip_detection { "关闭" } else { "开启" }
+                ),
+                format!("修改群头像权限: {}", x.policy),
+                format!("投票模式: {vote}"),
+            ]
+            .join("\n")
+        } else {
+            if !self.is_sender_admin(message).await? {
+                return "只有管理员可以修改群组设置".result();
+            }
+
+            let (key, value) = args.split_once(char::is_whitespace).unwrap_or((args, ""));
+            let value = value.trim();
+            let reset = value == "default";
+            match key {
+                "cooldown" => {
+                    let interval = if reset {
+                        None
+                    } else {
+                        let x = parse_duration(value)
+                            .and_then(|x| x.to_std().ok())
+                            .ok_or("冷却时间格式错误, 单位为秒, 或带 d/h/m/s 后缀".error())?;
+                        if x < MIN_INTERVAL {
+                            return format!("冷却时间不能少于 {} 秒", MIN_INTERVAL.as_secs())
+                                .result();
+                        }
+                        Some(x.as_secs())
+                    };
+                    settings::update(chat_id, |x| x.cooldown = interval)?;
+                    format!("已设置冷却时间: {} 秒", cooldown(chat_id).as_secs())
+                }
+                "color" => {
+                    let color = if reset {
+                        None
+                    } else {
+                        Some(parse_color(value).ok_or("背景颜色格式错误".error())?)
+                    };
+                    settings::update(chat_id, |x| x.color = color)?;
+                    format!(
+                        "已设置默认背景颜色: {}",
+                        color.map_or("默认".into(), |x| x.to_string())
+                    )
+                }
+                "align" => {
+                    let align = if reset {
+                        None
+                    } else {
+                        Some(parse_align(value).ok_or("截取位置格式错误".error())?)
+                    };
+                    settings::update(chat_id, |x| x.align = align)?;
+                    format!(
+                        "已设置默认截取位置: {}",
+                        align.map_or("默认".into(), |x| x.to_string())
+                    )
+                }
+                "detect" => {
+                    let skip_detection = match value {
+                        "on" | "default" => false,
+                        "off" => true,
+                        _ => return "请指定 on 或 off".result(),
+                    };
+                    settings::update(chat_id, |x| x.skip_detection = skip_detection)?;
+                    if skip_detection {
+                        "已关闭人脸检测".into()
+                    } else {
+                        "已开启人脸检测".into()
+                    }
+                }
+                "policy" => {
+                    let policy = if reset {
+                        Policy::default()
+                    } else {
+                        parse_policy(value).ok_or("请指定 admins、anyone 或 approval".error())?
+                    };
+                    settings::update(chat_id, |x| x.policy = policy)?;
+                    format!("已设置修改群头像权限: {policy}")
+                }
+                _ => return "请指定 cooldown、color、align、detect 或 policy".result(),
+            }
+        };
+
+        let input_message = InputMessage::text(text).reply_to(Some(message.id()));
+        self.send_message(chat, input_message).await?;
+        Ok(())
+    }
+
+    async fn preset(&mut self, message: &Message, args: &str) -> Result<(), Error> {
+        let chat = &message.chat();
+        let chat_id = chat.id();
+        if !chats::is_allowed(chat_id) {
+            return format!("尚未向本群组 ({chat_id}) 提供服务").result();
+        }
+
+        let (subcommand, args) = args.split_once(char::is_whitespace).unwrap_or((args, ""));
+        let args = args.trim();
+        if subcommand != "list" && !self.is_sender_admin(message).await? {
+            return "只有管理员可以管理预设".result();
+        }
+        let text = match subcommand {
+            "save" => {
+                let (name, options) = args.split_once(char::is_whitespace).unwrap_or((args, ""));
+                let name = name.trim_start_matches('@');
+                let options = options.trim();
+                if name.is_empty() || options.is_empty() {
+                    return "请指定预设名称和选项".result();
+                }
+                if options.split_whitespace().any(|x| x.starts_with('@')) {
+                    return "预设中不能引用其他预设".result();
+                }
+                Opt::parse(options, &Settings::default())?;
+
+                settings::update(chat_id, |x| x.presets.insert(name.into(), options.into()))?;
+                format!("已保存预设 @{name}: {options}")
+            }
+            "list" => {
+                let presets = settings::get(chat_id).presets;
+                if presets.is_empty() {
+                    return "本群组没有预设".result();
+                }
+
+                let mut text = String::new();
+                for (name, options) in presets {
+                    text.push_str(&format!("@{name}: {options}\n"));
+                }
+                text
+            }
+            "delete" => {
+                let name = args.trim_start_matches('@');
+                if settings::update(chat_id, |x| x.presets.remove(name))?.is_none() {
+                    return format!("没有名为 @{name} 的预设").result();
+                }
+                format!("已删除预设 @{name}")
+            }
+            _ => return "请指定 save、list 或 delete".result(),
+        };
+
+        let input_message = InputMessage::text(text).reply_to(Some(message.id()));
+        self.send_message(chat, input_message).await?;
+        Ok(())
+    }
+
+    async fn allow_chat(
+        &mut self,
+        message: &Message,
+        args: &str,
+        allow: bool,
+    ) -> Result<(), Error> {
+        if !message.sender().is_some_and(|x| chats::is_owner(x.id())) {
+            return "只有 bot 的所有者可以管理群组列表".result();
+        }
+
+        let chat_id = if args.is_empty() {
+            message.chat().id()
+        } else if let Ok(x) = i64::from_str(args) {
+            x
+        } else {
+            let username = args.trim_start_matches('@');
+            match self.resolve_username(username).await? {
+                Some(x @ (Chat::Group(_) | Chat::Channel(_))) => x.id(),
+                Some(_) => return format!("{args} 不是群组").result(),
+                None => return format!("找不到群组 {args}").result(),
+            }
+        };
+
+        let changed = if allow {
+            chats::allow(chat_id)?
+        } else {
+            chats::deny(chat_id)?
+        };
+        let text = match (allow, changed) {
+            (true, true) => format!("已开始向群组 ({chat_id}) 提供服务"),
+            (true, false) => format!("已经在向群组 ({chat_id}) 提供服务"),
+            (false, true) => format!("已停止向群组 ({chat_id}) 提供服务"),
+            (false, false) => format!("尚未向群组 ({chat_id}) 提供服务"),
+        };
+        let input_message = InputMessage::text(text).reply_to(Some(message.id()));
+        self.send_message(message.chat(), input_message).await?;
+        Ok(())
+    }
+
+    async fn chats(&mut self, message: &Message) -> Result<(), Error> {
+        if !message.sender().is_some_and(|x| chats::is_owner(x.id())) {
+            return "只有 bot 的所有者可以管理群组列表".result();
+        }
+
+        let list = chats::list();
+        let text = if list.is_empty() {
+            "尚未向任何群组提供服务".into()
+        } else {
+            let lines: Vec<_> = list
+                .into_iter()
+                .map(|(id, from_env)| {
+                    if from_env {
+                        format!("{id} (CHAT_LIST)")
+                    } else {
+                        id.to_string()
+                    }
+                })
+                .collect();
+            format!("正在提供服务的群组:\n{}", lines.join("\n"))
+        };
+        let input_message = InputMessage::text(text).reply_to(Some(message.id()));
+        self.send_message(message.chat(), input_message).await?;
+        Ok(())
+    }
+
+    async fn remove_avatar(&mut self, message: &Message) -> Result<(), Error> {
+        let chat = &message.chat();
+        if self.check_policy(chat, message.sender()).await? {
+            return "非管理员只能通过 /set_avatar 申请修改群头像".result();
+        }
+        // there is no avatar to vote on, so removing it would bypass the vote
+        if settings::get(chat.id()).vote.is_some() {
+            return "投票模式下不能移除群头像".result();
+        }
+        let mut chat_last_update = try_lock_chat(chat.id())?;
+        if chat_last_update.elapsed() < cooldown(chat.id()) {
+            return "技能冷却中".result();
+        }
+
+        self.edit_photo(chat, InputChatPhoto::Empty).await?;
+        chat_last_update.touch();
+        schedule::cancel_restore(chat.id())?;
+        Ok(())
+    }
+
+    async fn process_avatar(
+        &mut self,
+        media_message: &Message,
+        opt: &Opt,
+    ) -> Result<Avatar, Error> {
+        let mut file = None;
+        let mut is_square = false;
+        let mut is_video = false;
+        let mut error = None;
+        if let Some(media) = media_message.media() {
+            let mut download = None;
+            let mut mime = None;
+            let mut sticker_id = 0;
+            match &media {
+                Media::Photo(x) => {
+                    if let Some(x) = x.thumbs().largest() {
+                        download.replace(x.size() <= MAX_FILESIZE);
+                    }
+                }
+                Media::Document(x) => {
+                    mime = x.mime_type();
+                    match mime.and_then(|x| x.split_once('/').map(|x| x.0)) {
+                        Some("video" | "image") => {
+                            download.replace(x.size() <= MAX_FILESIZE as _);
+                            if let Some((width, height)) = x.resolution() {
+                                is_square = width == height;
+                            }
+                        }
+                        _ => error = Some("不支持的文件类型"),
+                    };
+                }
+                Media::Sticker(x) => {
+                    download.replace(x.document.size() <= MAX_FILESIZE as _);
+                    mime = x.document.mime_type();
+                    if let Some((width, height)) = x.document.resolution() {
+                        is_square = width == height;
+                    }
+                    sticker_id = x.document.id();
+                }
+                _ => {}
+            }
+
+            let mime = mime.map(str::to_string);
+            if let Some(download) = download {
+                if download {
+                    let mut buf = Vec::new();
+                    let mut downloader = self.iter_download(&Downloadable::Media(media));
+                    while let Some(x) = downloader.next().await? {
+                        buf.extend(x);
+                    }
+
+                    if let Some(x) = mime {
+                        if let Some(frame) = opt.frame {
+                            if x.starts_with("video/") {
+                                buf = video_to_png(buf, frame)?;
+                            } else if x == "application/x-tgsticker" {
+                                buf = tgs_to_png(buf, &format!("{sticker_id}"), frame)?;
+                            }
+                        } else if x.starts_with("video/") {
+                            is_video = true;
+                            if !is_square
+                                || !x.starts_with("video/mp4")
+                                || opt.is_trimmed()
+                                || opt.show_detect
+                                || !is_avatar_ready(&buf)?
+                            {
+                                buf = video_to_mp4(buf, opt)?;
+                                is_square = true
+                            }
+                        } else if x == "application/x-tgsticker" {
+                            is_video = true;
+                            if is_square {
+                                buf = tgs_to_mp4(buf, &format!("{sticker_id}"), opt.color)?;
+                            } else {
+                                let frame = Frame::Index(0);
+                                buf = tgs_to_png(buf, &format!("{sticker_id}"), frame)?;
+                            }
+                        }
+                    }
+                    file = Some(buf);
+                } else {
+                    error = Some("文件大小超出限制");
+                }
+            }
+        };
+
+        if file.is_none() {
+            if let Some(x) = media_message.url() {
+                error = None;
+                file = link_to_img(x).await?
+            }
+        }
+
+        let mut buf = match file {
+            Some(x) => x,
+            None => return error.unwrap_or("未检测到受支持的头像").result(),
+        };
+        is_video = is_video && is_square;
+        if !is_video {
+            image_to_png(&mut buf, opt)?;
+        }
+
+        Ok(Avatar {
+            data: buf,
+            is_video,
+            video_start_ts: opt.cover.filter(|_| is_video),
+            uploaded: None,
+        })
+    }
+
+    async fn set_avatar(&mut self, message: &Message, args: &str) -> Result<(), Error> {
+        let chat = &message.chat();
+        let chat_id = chat.id();
+        if !chats::is_allowed(chat_id) {
+            return format!("尚未向本群组 ({chat_id}) 提供服务").result();
+        }
+
+        let opt = Opt::parse(args, &settings::get(chat_id))?;
+        // dry runs never touch the avatar, so they neither take the lock nor wait for the cooldown
+        let chat_last_update = if opt.dry_run {
+            None
+        } else {
+            let x = try_lock_chat(chat_id)?;
+            if x.elapsed() < cooldown(chat_id) {
+                return "技能冷却中".result();
+            }
+            Some(x)
+        };
+
+        let restore_time = opt.expire.map(|x| x.time());
+        if restore_time.is_some_and(|x| x <= Utc::now()) {
+            return "恢复时间必须晚于当前时间".result();
+        }
+
+        let needs_approval = !opt.dry_run && self.check_policy(chat, message.sender()).await?;
+
+        let Some(reply_to) = message.reply_to_message_id() else {
+            return "使用 set_avatar 命令时请回复包含头像的消息 (照片、视频、贴纸、文件)".result();
+        };
+
+        let notify = Arc::new(Notify::new());
+        spawn({
+            let mut bot = self.clone();
+            let chat = chat.clone();
+            let notify = notify.clone();
+            let mut interval = interval(Duration::from_secs(8));
+            async move {
+                loop {
+                    select! {
+                        _ = notify.notified() => break,
+                        _ = interval.tick() => bot.set_typing(&chat).await?,
+                    }
+                }
+                Ok::<_, Error>(())
+            }
+        });
+        struct Notified(Arc<Notify>);
+        impl Drop for Notified {
+            fn drop(&mut self) {
+                self.0.notify_one();
+            }
+        }
+        let notify = Notified(notify);
+
+        let media_message = self
+            .get_messages_by_id(chat, &[reply_to])
+            .await?
+            .swap_remove(0)
+            .ok_or("读取回复的消息失败".error())?;
+        let mut avatar = self.process_avatar(&media_message, &opt).await?;
+
+        if let Some(chat_last_update) = chat_last_update {
+            drop(notify);
+            let source = message_source(message, Some(media_message.id()), args);
+            self.submit_avatar(
+                message,
+                chat_last_update,
+                avatar,
+                source,
+                restore_time,
+                needs_approval,
+            )
+            .await?;
+        } else {
+            let is_video = avatar.is_video;
+            let cover = match opt.cover.filter(|_| is_video) {
+                Some(x) => Some(video_to_png(avatar.data.clone(), Frame::Time(x))?),
+                None => None,
+            };
+            let uploaded = self.upload_avatar(&mut avatar).await?;
+
+            drop(notify);
+
+            let mut input_message = InputMessage::default().reply_to(Some(message.id()));
+            if !opt.show_detect {
+                input_message = input_message.reply_markup(&apply_markup());
+            }
+            if is_video {
+                input_message = input_message.document(uploaded).mime_type("video/mp4");
+            } else {
+                input_message = input_message.photo(uploaded);
+            }
+            let preview = self.send_message(chat, input_message).await?;
+
+            if !opt.show_detect {
+                let preview_id = preview.id();
+                let source = message_source(message, Some(media_message.id()), args);
+                pending::insert_preview(chat_id, preview_id, avatar, source, opt.expire);
+                spawn(async move {
+                    sleep(PREVIEW_TIMEOUT).await;
+                    pending::remove(chat_id, preview_id);
+                });
+            }
+
+            if let Some(x) = cover {
+                let uploaded = self.upload_file(x, "cover.png").await?;
+                let input_message = InputMessage::default()
+                    .reply_to(Some(message.id()))
+                    .photo(uploaded);
+                self.send_message(chat, input_message).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Options stored before they were validated, or naming a preset deleted since, fail on every
+/// run, so the job or pool item holding them is dropped instead of retried.
+fn check_stored_options(chat_id: i64, options: &str) -> Result<(), Error> {
+    match Opt::parse(options, &settings::get(chat_id)) {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            let error = e.message().unwrap_or("未知错误");
+            format!("选项 {options} 已失效, 已将其移除: {error}").result()
+        }
+    }
+}
+
+async fn report_failure(bot: &mut Client, chat: PackedChat, task: &str, e: Error) {
+    if let Some(error) = e.message() {
+        let text = format!("{task}执行失败: {error}");
+        if let Err(e) = bot.send_message(chat, InputMessage::text(text)).await {
+            println!("Failed to report failure of {task}: {e}");
+        }
+    }
+}
+
+pub async fn run_schedule(client: Client) {
+    let mut interval = interval(SCHEDULE_INTERVAL);
+    loop {
+        interval.tick().await;
+        match schedule::take_due() {
+            Ok(jobs) => {
+                for job in jobs {
+                    let mut bot = client.clone();
+                    spawn(async move {
+                        let ret = match &job.action {
+                            Action::SetAvatar { options, .. } => {
+                                check_stored_options(job.chat_id, options)
+                            }
+                            Action::Restore { .. } => Ok(()),
+                        };
+                        let e = match ret {
+                            Ok(()) => {
+                                let ret = timeout(SET_TIMEOUT, bot.run_job(&job))
+                                    .await
+                                    .unwrap_or("请求处理超时".result());
+                                match ret {
+                                    Ok(()) => None,
+                                    Err(e) => {
+                                        println!(
+                                            "Failed to run scheduled job {} (attempt {}): {e}",
+                                            job.id, job.attempts
+                                        );
+                                        if job.attempts < schedule::MAX_ATTEMPTS {
+                                            return;
+                                        }
+                                        Some(e)
+                                    }
+                                }
+                            }
+                            Err(e) => {
+                                println!("Dropping scheduled job {}: {e}", job.id);
+                                Some(e)
+                            }
+                        };
+                        if let Err(e) = schedule::finish(&job) {
+                            println!("Failed to remove scheduled job {}: {e}", job.id);
+                        }
+                        if let (Some(e), Ok(chat)) = (e, job.chat()) {
+                            let task = format!("定时任务 {}", job.id);
+                            report_failure(&mut bot, chat, &task, e).await;
+                        }
+                    });
+                }
+            }
+            Err(e) => println!("Failed to load scheduled jobs: {e}"),
+        }
+
+        match pool::take_due() {
+            Ok(items) => {
+                for (chat, item) in items {
+                    let mut bot = client.clone();
+                    spawn(async move {
+                        if let Err(e) = check_stored_options(chat.id, &item.options) {
+                            println!("Dropping avatar pool item of {}: {e}", chat.id);
+                            if let Err(e) = pool::discard(chat.id, &item) {
+                                println!("Failed to remove avatar pool item of {}: {e}", chat.id);
+                            }
+                            report_failure(&mut bot, chat, "头像轮换", e).await;
+                            return;
+                        }
+
+                        let ret = timeout(SET_TIMEOUT, bot.rotate_pool(chat, &item))
+                            .await
+                            .unwrap_or("请求处理超时".result());
+                        if let Err(e) = ret {
+                            println!("Failed to rotate avatar pool of {}: {e}", chat.id);
+                            report_failure(&mut bot, chat, "头像轮换", e).await;
+                        }
+                    });
+                }
+            }
+            Err(e) => println!("Failed to load avatar pools: {e}"),
+        }
+    }
+}
+
+pub fn handle_update(client: &Client, update: Update) {
+    let username = USERNAME.get().unwrap();
+    match update {
+        Update::NewMessage(message) if !message.outgoing() => {
+            if let Some(command) = message.bot_command(username) {
+                let mut bot = client.clone();
                 spawn(async move {
                     let ret = match command {
                         Command::Help => bot.help(&message).await,
-                        Command::SetAvatar(opt) => {
-                            timeout(SET_TIMEOUT, bot.set_avatar(&message, &opt))
+                        Command::SetAvatar(args) => {
+                            timeout(SET_TIMEOUT, bot.set_avatar(&message, &args))
                                 .await
                                 .unwrap_or("请求处理超时".result())
                         }
+                        Command::RemoveAvatar => bot.remove_avatar(&message).await,
+                        Command::GetAvatar => bot.get_avatar(&message).await,
+                        Command::History => bot.history(&message).await,
+                        Command::Revert(args) => bot.revert(&message, &args).await,
+                        Command::Schedule(args) => bot.schedule(&message, &args).await,
+                        Command::Pool(args) => bot.pool(&message, &args).await,
+                        Command::Vote(args) => bot.vote(&message, &args).await,
+                        Command::Policy(args) => bot.policy(&message, &args).await,
+                        Command::Settings(args) => bot.settings(&message, &args).await,
+                        Command::Preset(args) => bot.preset(&message, &args).await,
+                        Command::Allow(args) => bot.allow_chat(&message, &args, true).await,
+                        Command::Deny(args) => bot.allow_chat(&message, &args, false).await,
+                        Command::Chats => bot.chats(&message).await,
                     };
                     if let Err(e) = ret {
                         let error = e.message().unwrap_or_else(|| {
@@ -465,6 +1980,35 @@ pub fn handle_update(client: &Client, update: Update) {
                 });
             }
         }
+        Update::CallbackQuery(query) => {
+            let mut bot = client.clone();
+            spawn(async move {
+                if let Err(e) = bot.callback(&query).await {
+                    let error = e.message().unwrap_or_else(|| {
+                        println!("Failed to handle callback query: {e}");
+                        "发生了一些错误"
+                    });
+                    if let Err(e) = query.answer().alert(error).send().await {
+                        println!("Failed to answer callback query \"{error}\": {e}");
+                    }
+                }
+            });
+        }
         _ => {}
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn threshold_as_count_or_percentage() {
+        assert!(matches!(parse_threshold("3"), Some(Threshold::Count(3))));
+        assert!(matches!(parse_threshold("50%"), Some(Threshold::Fraction(x)) if x == 0.5));
+        assert_eq!(parse_threshold("7%").unwrap().to_string(), "7% 成员");
+        for x in ["0", "0%", "101%", "-1", "abc", "%"] {
+            assert!(parse_threshold(x).is_none(), "{x}");
+        }
+    }
+}
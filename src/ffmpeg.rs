@@ -1,19 +1,106 @@
-use std::io::Read;
+use std::io::{Cursor, Read};
 use std::slice;
 use std::sync::atomic::AtomicUsize;
 use std::sync::atomic::Ordering::Relaxed;
 use std::sync::Arc;
 
+use image::ImageFormat::Png;
+use image::RgbaImage;
 use rsmpeg::avcodec::{AVCodec, AVCodecContext};
-use rsmpeg::avformat::{AVFormatContextInput, AVIOContextContainer, AVIOContextCustom};
-use rsmpeg::avutil::{AVFrameWithImage, AVImage, AVMem};
+use rsmpeg::avformat::{
+    AVFormatContextInput, AVIOContextContainer, AVIOContextCustom, AVStreamRef,
+};
+use rsmpeg::avutil::{AVFrame, AVMem, AVRational};
 use rsmpeg::error::RsmpegError;
 use rsmpeg::ffi;
 use rsmpeg::swscale::SwsContext;
 
 use crate::error::Error;
+use crate::image::rotate_image;
+use crate::opt::Frame;
 
-pub fn video_to_png(data: Vec<u8>) -> Result<Vec<u8>, Error> {
+pub fn seconds_to_ts(seconds: f64, time_base: AVRational) -> i64 {
+    (seconds * time_base.den as f64 / time_base.num as f64) as i64
+}
+
+pub fn seek_frame(
+    format_context: &mut AVFormatContextInput,
+    stream_index: usize,
+    timestamp: i64,
+) -> Result<(), Error> {
+    let ret = unsafe {
+        ffi::av_seek_frame(
+            format_context.as_mut_ptr(),
+            stream_index as _,
+            timestamp,
+            ffi::AVSEEK_FLAG_BACKWARD as _,
+        )
+    };
+    if ret < 0 {
+        return Err("Failed to seek frame".into());
+    }
+
+    Ok(())
+}
+
+pub fn stream_rotation(stream: &AVStreamRef) -> i32 {
+    let codecpar = stream.codecpar();
+    let side_data = unsafe {
+        ffi::av_packet_side_data_get(
+            codecpar.coded_side_data,
+            codecpar.nb_coded_side_data,
+            ffi::AV_PKT_DATA_DISPLAYMATRIX,
+        )
+    };
+    if side_data.is_null() {
+        return 0;
+    }
+
+    let angle = unsafe { ffi::av_display_rotation_get((*side_data).data as *const i32) };
+    if angle.is_nan() {
+        return 0;
+    }
+
+    let rotation = (-angle.round() as i32).rem_euclid(360);
+    (rotation + 45) / 90 * 90 % 360
+}
+
+pub fn frame_to_image(frame: &AVFrame) -> Result<RgbaImage, Error> {
+    let mut sws_context = SwsContext::get_context(
+        frame.width,
+        frame.height,
+        frame.format,
+        frame.width,
+        frame.height,
+        ffi::AV_PIX_FMT_RGBA,
+        ffi::SWS_FAST_BILINEAR | ffi::SWS_ACCURATE_RND,
+        None,
+        None,
+        None,
+    )
+    .ok_or("Failed to get sws_context")?;
+
+    let mut rgba_frame = AVFrame::new();
+    rgba_frame.set_format(ffi::AV_PIX_FMT_RGBA);
+    rgba_frame.set_width(frame.width);
+    rgba_frame.set_height(frame.height);
+    rgba_frame.alloc_buffer()?;
+    sws_context.scale_frame(frame, 0, frame.height, &mut rgba_frame)?;
+
+    let width = frame.width as usize;
+    let height = frame.height as usize;
+    let linesize = rgba_frame.linesize[0] as usize;
+    let data = unsafe { slice::from_raw_parts(rgba_frame.data[0], linesize * height) };
+    let mut buf = Vec::with_capacity(width * height * 4);
+    for row in data.chunks(linesize) {
+        buf.extend_from_slice(&row[..width * 4]);
+    }
+
+    let image = RgbaImage::from_raw(width as _, height as _, buf).ok_or("Invalid frame data")?;
+    Ok(image)
+}
+
+pub fn video_to_png(data: Vec<u8>, frame: Frame) -> Result<Vec<u8>, Error> {
     let cur1 = Arc::new(AtomicUsize::new(0));
     let cur2 = cur1.clone();
 
@@ -52,7 +139,7 @@ pub fn video_to_png(data: Vec<u8>) -> Result<Vec<u8>, Error> {
     let mut input_format_context =
         AVFormatContextInput::from_io_context(AVIOContextContainer::Custom(io_context))?;
 
-    let (video_stream_index, mut decode_context) = {
+    let (video_stream_index, time_base, rotation, mut decode_context) = {
         let (stream_index, mut decoder) = input_format_context
             .find_best_stream(ffi::AVMEDIA_TYPE_VIDEO)?
             .ok_or("Failed to find the best stream")?;
@@ -69,10 +156,25 @@ pub fn video_to_png(data: Vec<u8>) -> Result<Vec<u8>, Error> {
         decode_context.apply_codecpar(&stream.codecpar())?;
         decode_context.open(None)?;
 
-        (stream_index, decode_context)
+        (
+            stream_index,
+            stream.time_base,
+            stream_rotation(stream),
+            decode_context,
+        )
     };
 
-    let cover_frame = loop {
+    let (timestamp, mut index) = match frame {
+        Frame::Index(x) => (0, x),
+        Frame::Time(x) => (seconds_to_ts(x, time_base), 0),
+    };
+    if timestamp > 0 {
+        seek_frame(&mut input_format_context, video_stream_index, timestamp)?;
+    }
+
+    // A frame past the end of the video falls back to the last one, as in a sticker.
+    let mut last_frame = None;
+    let cover_frame = 'decode: loop {
         let cover_packet = loop {
             match input_format_context.read_packet()? {
                 Some(x) if x.stream_index != video_stream_index as i32 => {}
@@ -81,69 +183,30 @@ pub fn video_to_png(data: Vec<u8>) -> Result<Vec<u8>, Error> {
         };
 
         decode_context.send_packet(cover_packet.as_ref())?;
-        match decode_context.receive_frame() {
-            Ok(x) => break x,
-            Err(RsmpegError::DecoderDrainError) => {}
-            Err(e) => return Err(e.into()),
+        loop {
+            match decode_context.receive_frame() {
+                Ok(x) if x.pts < timestamp => last_frame = Some(x),
+                Ok(x) if index > 0 => {
+                    index -= 1;
+                    last_frame = Some(x);
+                }
+                Ok(x) => break 'decode x,
+                Err(RsmpegError::DecoderDrainError) => break,
+                Err(RsmpegError::DecoderFlushedError) => {
+                    break 'decode last_frame.ok_or("Can't find video cover frame")?;
+                }
+                Err(e) => return Err(e.into()),
+            }
         }
 
         if cover_packet.is_none() {
-            return Err("Can't find video cover frame".into());
+            break last_frame.ok_or("Can't find video cover frame")?;
         }
     };
 
-    let mut encode_context = {
-        let encoder = AVCodec::find_encoder(ffi::AV_CODEC_ID_PNG).ok_or("Encoder not found")?;
-        let mut encode_context = AVCodecContext::new(&encoder);
-
-        encode_context.set_bit_rate(decode_context.bit_rate);
-        encode_context.set_width(decode_context.width);
-        encode_context.set_height(decode_context.height);
-        encode_context.set_time_base(ffi::AVRational { num: 1, den: 1 });
-        encode_context.set_pix_fmt(ffi::AV_PIX_FMT_RGBA);
-        encode_context.open(None)?;
-
-        encode_context
-    };
-
-    let scaled_cover_packet = {
-        let mut sws_context = SwsContext::get_context(
-            decode_context.width,
-            decode_context.height,
-            decode_context.pix_fmt,
-            encode_context.width,
-            encode_context.height,
-            encode_context.pix_fmt,
-            ffi::SWS_FAST_BILINEAR | ffi::SWS_ACCURATE_RND,
-            None,
-            None,
-            None,
-        )
-        .ok_or("Invalid sws_context parameter")?;
-        let image_buffer = AVImage::new(
-            encode_context.pix_fmt,
-            encode_context.width,
-            encode_context.height,
-            1,
-        )
-        .ok_or("Invalid image_buffer parameter")?;
-
-        let mut scaled_cover_frame = AVFrameWithImage::new(image_buffer);
-
-        sws_context.scale_frame(
-            &cover_frame,
-            0,
-            decode_context.height,
-            &mut scaled_cover_frame,
-        )?;
-
-        encode_context.send_frame(Some(&scaled_cover_frame))?;
-        encode_context.receive_packet()?
-    };
-
-    let data = unsafe {
-        slice::from_raw_parts(scaled_cover_packet.data, scaled_cover_packet.size as usize)
-    };
+    let image = rotate_image(frame_to_image(&cover_frame)?, rotation);
+    let mut data = Vec::new();
+    image.write_to(&mut Cursor::new(&mut data), Png)?;
 
-    Ok(data.into())
+    Ok(data)
 }
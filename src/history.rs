@@ -0,0 +1,92 @@
+use std::cmp::min;
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+
+use crate::command::Avatar;
+use crate::error::Error;
+use crate::ffmpeg::video_to_png;
+use crate::image::thumbnail_grid;
+use crate::opt::Frame;
+use crate::store::{AvatarFile, Store};
+
+const MAX_HISTORY: usize = 10;
+const THUMBNAIL_SIZE: u32 = 160;
+const THUMBNAIL_COLUMNS: usize = 5;
+
+lazy_static! {
+    static ref HISTORY: Store<HashMap<i64, Vec<Entry>>> = Store::open("history.json");
+}
+
+/// Load the avatar history from disk.
+pub fn load() {
+    lazy_static::initialize(&HISTORY);
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Source {
+    pub message_id: Option<i32>,
+    pub sender: Option<String>,
+    #[serde(default)]
+    pub sender_id: Option<i64>,
+    pub command: String,
+    /// Options of `/set_avatar` the avatar was made with.
+    #[serde(default)]
+    pub options: String,
+}
+
+/// The avatar itself is kept as a local copy, as the file references of Telegram expire.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Entry {
+    pub time: DateTime<Utc>,
+    #[serde(flatten)]
+    pub avatar: AvatarFile,
+    #[serde(flatten)]
+    pub source: Source,
+}
+
+impl Entry {
+    fn thumbnail(&self) -> Result<Vec<u8>, Error> {
+        let avatar = self.avatar.load()?;
+        if avatar.is_video {
+            let frame = Frame::Time(avatar.video_start_ts.unwrap_or(0.0));
+            video_to_png(avatar.data, frame)
+        } else {
+            Ok(avatar.data)
+        }
+    }
+}
+
+pub fn record(chat_id: i64, avatar: &Avatar, source: Source) -> Result<(), Error> {
+    let entry = Entry {
+        time: Utc::now(),
+        avatar: AvatarFile::save(&format!("avatar/{chat_id}"), avatar)?,
+        source,
+    };
+    let expired = HISTORY.update(|x| {
+        let entries = x.entry(chat_id).or_default();
+        entries.insert(0, entry);
+        entries.split_off(min(entries.len(), MAX_HISTORY))
+    })?;
+    for i in expired {
+        i.avatar.remove();
+    }
+
+    Ok(())
+}
+
+/// Newest first, so index 0 is the most recently applied avatar.
+pub fn entries(chat_id: i64) -> Vec<Entry> {
+    HISTORY.read(|x| x.get(&chat_id).cloned().unwrap_or_default())
+}
+
+pub fn thumbnails(entries: &[Entry]) -> Result<Vec<u8>, Error> {
+    let images = entries
+        .iter()
+        .map(Entry::thumbnail)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    thumbnail_grid(&images, THUMBNAIL_SIZE, THUMBNAIL_COLUMNS)
+}
@@ -3,16 +3,18 @@ use std::io::{Cursor, Write};
 use std::slice;
 
 use flate2::write::GzDecoder;
+use image::imageops::FilterType;
+use image::imageops::{rotate180, rotate270, rotate90};
 use image::math::Rect;
-use image::ImageFormat::Png;
-use image::{load_from_memory, DynamicImage, GenericImage, Rgba, RgbaImage};
+use image::ImageFormat::{Jpeg, Png};
+use image::{guess_format, load_from_memory, DynamicImage, GenericImage, Rgba, RgbaImage};
 use imageproc::drawing::draw_hollow_rect_mut;
 use imageproc::rect;
 use rlottie::{Animation, Surface};
 
-use crate::command::{Align, Color, Opt};
 use crate::error::Error;
 use crate::opencv::detect_animeface;
+use crate::opt::{Align, Color, Frame, Opt};
 
 #[inline]
 fn alpha_composite(pixel: &mut [u8; 4], color: [i32; 3]) {
@@ -30,6 +32,14 @@ unsafe fn split_pixel(data: &mut [u8]) -> &mut [[u8; 4]] {
     slice::from_raw_parts_mut(data.as_mut_ptr() as *mut [u8; 4], data.len() / 4)
 }
 
+/// Only PNG and JPEG are expected, the latter from photos downloaded from Telegram.
+pub fn image_extension(data: &[u8]) -> &'static str {
+    match guess_format(data) {
+        Ok(Jpeg) => "jpg",
+        _ => "png",
+    }
+}
+
 pub fn set_color(data: &mut [u8], color: [i32; 3]) {
     let data = unsafe { split_pixel(data) };
     for i in data {
@@ -79,25 +89,52 @@ pub fn trans_flag(data: &mut [u8], width: usize, height: usize, rgb: bool) {
     }
 }
 
+pub fn square_rect(width: u32, height: u32, align: &Align) -> Rect {
+    let length = min(width, height);
+    let (x, y) = if width > height {
+        let diff = width - length;
+        match align {
+            Align::Left => (0, 0),
+            Align::Right => (diff, 0),
+            _ => (diff / 2, 0),
+        }
+    } else {
+        let diff = height - length;
+        match align {
+            Align::Top => (0, 0),
+            Align::Bottom => (0, diff),
+            _ => (0, diff / 2),
+        }
+    };
+
+    Rect {
+        x,
+        y,
+        width: length,
+        height: length,
+    }
+}
+
 fn square_image(img: &mut RgbaImage, align: &Align) -> Option<RgbaImage> {
-    let height = img.height();
-    let width = img.width();
-    if width >= height {
+    if img.width() == img.height() {
         return None;
     }
 
-    let subimage = match align {
-        Align::Top => img.sub_image(0, 0, width, width),
-        Align::Bottom => img.sub_image(0, height - width, width, width),
-        Align::Center => {
-            let y = (height - width) / 2;
-            img.sub_image(0, y, width, width)
-        }
-    };
+    let rect = square_rect(img.width(), img.height(), align);
+    let subimage = img.sub_image(rect.x, rect.y, rect.width, rect.height);
 
     Some(subimage.to_image())
 }
 
+pub fn rotate_image(img: RgbaImage, rotation: i32) -> RgbaImage {
+    match rotation {
+        90 => rotate90(&img),
+        180 => rotate180(&img),
+        270 => rotate270(&img),
+        _ => img,
+    }
+}
+
 fn draw_thickness_rect(img: &mut RgbaImage, rect: &Rect, color: Rgba<u8>, thickness: u32) {
     for i in 0..thickness {
         draw_hollow_rect_mut(
@@ -137,6 +174,26 @@ fn face_image_rect(img: &RgbaImage, face: &Rect) -> Rect {
     }
 }
 
+pub fn select_face(img: &RgbaImage, detect: &[Rect]) -> Option<Rect> {
+    let mut select = None;
+    for i in detect {
+        match select {
+            None => select = Some(i),
+            Some(x) if i.width > x.width => select = Some(i),
+            _ => {}
+        }
+    }
+
+    select.map(|x| face_image_rect(img, x))
+}
+
+pub fn detect_faces(img: &RgbaImage) -> Result<Vec<Rect>, Error> {
+    let mut data = Vec::new();
+    img.write_to(&mut Cursor::new(&mut data), Png)?;
+
+    detect_animeface(&data)
+}
+
 pub fn image_to_png(data: &mut Vec<u8>, opt: &Opt) -> Result<(), Error> {
     let image = load_from_memory(data)?;
 
@@ -146,17 +203,8 @@ pub fn image_to_png(data: &mut Vec<u8>, opt: &Opt) -> Result<(), Error> {
             rgba = x;
         }
     } else {
-        let mut select = None;
         let detect = detect_animeface(data)?;
-        for i in &detect {
-            match select {
-                None => select = Some(i),
-                Some(x) if i.width > x.width => select = Some(i),
-                _ => {}
-            }
-        }
-
-        let select = select.map(|x| face_image_rect(&rgba, x));
+        let select = select_face(&rgba, &detect);
         if opt.show_detect {
             for i in &detect {
                 draw_thickness_rect(&mut rgba, i, Rgba([0, 0, 0, 0xff]), i.width / 64);
@@ -186,13 +234,21 @@ pub fn image_to_png(data: &mut Vec<u8>, opt: &Opt) -> Result<(), Error> {
     Ok(())
 }
 
-pub fn tgs_to_png(data: Vec<u8>, cache_key: &str) -> Result<Vec<u8>, Error> {
+pub fn tgs_to_png(data: Vec<u8>, cache_key: &str, frame: Frame) -> Result<Vec<u8>, Error> {
     let mut json_data = Vec::new();
     GzDecoder::new(&mut json_data).write_all(&data)?;
     let mut animation =
         Animation::from_data(json_data, cache_key, "/nonexistent").ok_or("Invalid lottie data")?;
     let mut surface = Surface::new(animation.size());
-    animation.render(0, &mut surface);
+    let last_frame = animation
+        .totalframe()
+        .checked_sub(1)
+        .ok_or("Empty lottie animation")?;
+    let frame_index = match frame {
+        Frame::Index(x) => x,
+        Frame::Time(x) => (x * animation.framerate()) as usize,
+    };
+    animation.render(min(frame_index, last_frame), &mut surface);
 
     let mut rgba = RgbaImage::new(surface.width() as _, surface.height() as _);
     for (x, y) in rgba.pixels_mut().zip(surface.data()) {
@@ -203,3 +259,60 @@ pub fn tgs_to_png(data: Vec<u8>, cache_key: &str) -> Result<Vec<u8>, Error> {
     DynamicImage::ImageRgba8(rgba).write_to(&mut Cursor::new(&mut png_data), Png)?;
     Ok(png_data)
 }
+
+pub fn thumbnail_grid(images: &[Vec<u8>], size: u32, columns: usize) -> Result<Vec<u8>, Error> {
+    let columns = min(columns, images.len()).max(1);
+    let rows = images.len().div_ceil(columns);
+    let mut grid = RgbaImage::from_pixel(
+        columns as u32 * size,
+        rows as u32 * size,
+        Rgba([0xff, 0xff, 0xff, 0xff]),
+    );
+    for (i, data) in images.iter().enumerate() {
+        let thumbnail = load_from_memory(data)?
+            .resize_to_fill(size, size, FilterType::Triangle)
+            .into_rgba8();
+        let x = (i % columns) as u32 * size;
+        let y = (i / columns) as u32 * size;
+        grid.copy_from(&thumbnail, x, y)?;
+    }
+
+    let mut png_data = Vec::new();
+    DynamicImage::ImageRgba8(grid).write_to(&mut Cursor::new(&mut png_data), Png)?;
+    Ok(png_data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn square(width: u32, height: u32, align: Align) -> (u32, u32, u32) {
+        let x = square_rect(width, height, &align);
+        assert_eq!(x.width, x.height);
+        (x.x, x.y, x.width)
+    }
+
+    #[test]
+    fn square_rect_of_wide_images() {
+        assert_eq!(square(300, 100, Align::Left), (0, 0, 100));
+        assert_eq!(square(300, 100, Align::Right), (200, 0, 100));
+        assert_eq!(square(300, 100, Align::Center), (100, 0, 100));
+        assert_eq!(square(300, 100, Align::Top), (100, 0, 100));
+        assert_eq!(square(301, 100, Align::Center), (100, 0, 100));
+    }
+
+    #[test]
+    fn square_rect_of_tall_images() {
+        assert_eq!(square(100, 300, Align::Top), (0, 0, 100));
+        assert_eq!(square(100, 300, Align::Bottom), (0, 200, 100));
+        assert_eq!(square(100, 300, Align::Center), (0, 100, 100));
+        assert_eq!(square(100, 300, Align::Left), (0, 100, 100));
+    }
+
+    #[test]
+    fn square_rect_of_square_images() {
+        for align in [Align::Top, Align::Left, Align::Center] {
+            assert_eq!(square(100, 100, align), (0, 0, 100));
+        }
+    }
+}
@@ -6,16 +6,28 @@ use grammers_session::Session;
 use rsmpeg::ffi;
 use tokio::select;
 use tokio::signal::ctrl_c;
+use tokio::task::spawn;
 
-use crate::command::{handle_update, LAST_UPDATE};
+use crate::chats::CHAT_LIST;
+use crate::command::{handle_update, run_schedule};
 use crate::error::Error;
+use crate::video::video_encoder;
 
+mod chats;
 mod command;
 mod error;
 mod ffmpeg;
+mod history;
 mod image;
 mod opencv;
 mod opengraph;
+mod opt;
+mod pending;
+mod pool;
+mod schedule;
+mod settings;
+mod state;
+mod store;
 mod video;
 
 pub static USERNAME: OnceLock<String> = OnceLock::new();
@@ -23,6 +35,10 @@ pub static USERNAME: OnceLock<String> = OnceLock::new();
 #[tokio::main]
 async fn main() -> Result<(), Error> {
     unsafe { ffi::av_log_set_level(ffi::AV_LOG_ERROR as i32) };
+    match video_encoder() {
+        Some(x) => println!("Using video encoder: {x}"),
+        None => println!("No video encoder available, video avatars are disabled"),
+    }
 
     let api_id = env::var("API_ID")
         .expect("API_ID")
@@ -32,7 +48,14 @@ async fn main() -> Result<(), Error> {
     let token = env::var("BOT_TOKEN").expect("BOT_TOKEN");
     let session_file = env::var("SESSION_FILE").expect("SESSION_FILE");
 
-    lazy_static::initialize(&LAST_UPDATE);
+    lazy_static::initialize(&CHAT_LIST);
+    // Stores are read on first use, so load them here to fail on a broken file at startup.
+    chats::load();
+    settings::load();
+    state::load();
+    history::load();
+    schedule::load();
+    pool::load();
 
     println!("Connecting to Telegram...");
     let client = Client::connect(Config {
@@ -54,6 +77,8 @@ async fn main() -> Result<(), Error> {
     let username = client.get_me().await?.username().unwrap_or_default().into();
     USERNAME.set(username)?;
 
+    spawn(run_schedule(client.clone()));
+
     println!("Handling messages...");
     loop {
         let update = match select! {
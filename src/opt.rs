@@ -0,0 +1,476 @@
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+
+use chrono::{DateTime, Days, Local, NaiveDate, NaiveDateTime, NaiveTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, IntoErrorMessage};
+use crate::settings::Settings;
+
+const OPTION_FLAGS: &[&str] = &[
+    "t", "top", "b", "bottom", "l", "left", "r", "right", "c", "center", "d", "dry", "s", "show",
+    "tr", "trans",
+];
+const OPTION_KEYS: &[&str] = &[
+    "start", "from", "end", "len", "cover", "frame", "until", "for", "color", "align",
+];
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Color {
+    Rgb([i32; 3]),
+    Trans,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Align {
+    Top,
+    Bottom,
+    Left,
+    Right,
+    Center,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Frame {
+    Index(usize),
+    Time(f64),
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Expire {
+    At(DateTime<Local>),
+    After(TimeDelta),
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Opt {
+    pub color: Color,
+    pub align: Option<Align>,
+    pub dry_run: bool,
+    pub show_detect: bool,
+    pub start: Option<f64>,
+    pub end: Option<f64>,
+    pub length: Option<f64>,
+    pub cover: Option<f64>,
+    pub frame: Option<Frame>,
+    pub expire: Option<Expire>,
+}
+
+fn parse_time(time: &str) -> Option<f64> {
+    let mut seconds = 0.0;
+    for x in time.split(':') {
+        let x = f64::from_str(x)
+            .ok()
+            .filter(|x| x.is_finite() && *x >= 0.0)?;
+        seconds = seconds * 60.0 + x;
+    }
+
+    Some(seconds)
+}
+
+pub fn parse_align(align: &str) -> Option<Align> {
+    match align {
+        "t" | "top" => Some(Align::Top),
+        "b" | "bottom" => Some(Align::Bottom),
+        "l" | "left" => Some(Align::Left),
+        "r" | "right" => Some(Align::Right),
+        "c" | "center" => Some(Align::Center),
+        _ => None,
+    }
+}
+
+/// Parse a color alias or a hex RGB color of 3 or 6 digits, with an optional `#` prefix.
+pub fn parse_color(color: &str) -> Option<Color> {
+    match color {
+        "tr" | "trans" => Some(Color::Trans),
+        x => {
+            let hex = x.strip_prefix('#').unwrap_or(x);
+            if !hex.bytes().all(|x| x.is_ascii_hexdigit()) {
+                return None;
+            }
+            let hex = match hex.len() {
+                3 => hex.chars().flat_map(|x| [x, x]).collect(),
+                6 => hex.to_string(),
+                _ => return None,
+            };
+            let [_, rgb @ ..] = u32::from_str_radix(&hex, 16)
+                .ok()?
+                .to_be_bytes()
+                .map(|x| x as _);
+            Some(Color::Rgb(rgb))
+        }
+    }
+}
+
+fn parse_frame(frame: &str) -> Option<Frame> {
+    if let Ok(x) = usize::from_str(frame) {
+        return Some(Frame::Index(x));
+    }
+
+    parse_time(frame.strip_suffix('s').unwrap_or(frame)).map(Frame::Time)
+}
+
+pub fn parse_duration(duration: &str) -> Option<TimeDelta> {
+    let (value, unit) = match duration.char_indices().last()? {
+        (i, 'd') => (&duration[..i], 86400.0),
+        (i, 'h') => (&duration[..i], 3600.0),
+        (i, 'm') => (&duration[..i], 60.0),
+        (i, 's') => (&duration[..i], 1.0),
+        _ => (duration, 1.0),
+    };
+
+    TimeDelta::try_milliseconds((parse_time(value)? * unit * 1000.0) as _)
+}
+
+pub fn parse_datetime(datetime: &str) -> Option<DateTime<Local>> {
+    let datetime = if let Ok(x) = NaiveDateTime::parse_from_str(datetime, "%Y-%m-%dT%H:%M") {
+        x
+    } else if let Ok(x) = NaiveDate::parse_from_str(datetime, "%Y-%m-%d") {
+        x.and_time(NaiveTime::MIN)
+    } else {
+        let now = Local::now().naive_local();
+        let x = now
+            .date()
+            .and_time(NaiveTime::parse_from_str(datetime, "%H:%M").ok()?);
+        if x > now {
+            x
+        } else {
+            x.checked_add_days(Days::new(1))?
+        }
+    };
+
+    datetime.and_local_timezone(Local).earliest()
+}
+
+impl Expire {
+    pub fn time(&self) -> DateTime<Utc> {
+        match self {
+            Self::At(x) => x.with_timezone(&Utc),
+            Self::After(x) => Utc::now() + *x,
+        }
+    }
+}
+
+impl Opt {
+    pub fn is_trimmed(&self) -> bool {
+        self.start.is_some() || self.end.is_some() || self.length.is_some()
+    }
+}
+
+impl Display for Align {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Top => write!(f, "顶部"),
+            Self::Bottom => write!(f, "底部"),
+            Self::Left => write!(f, "左侧"),
+            Self::Right => write!(f, "右侧"),
+            Self::Center => write!(f, "中间"),
+        }
+    }
+}
+
+impl Display for Color {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Rgb([r, g, b]) => write!(f, "#{r:02x}{g:02x}{b:02x}"),
+            Self::Trans => write!(f, "跨性别旗"),
+        }
+    }
+}
+
+/// Replace every `@name` in the options with the options saved in that preset.
+pub fn expand_presets(opt: &str, settings: &Settings) -> Result<String, Error> {
+    let mut tokens = Vec::new();
+    for x in opt.split_whitespace() {
+        match x.strip_prefix('@') {
+            Some(name) => match settings.presets.get(name) {
+                Some(x) => tokens.extend(x.split_whitespace()),
+                None => return format!("没有名为 @{name} 的预设").result(),
+            },
+            None => tokens.push(x),
+        }
+    }
+
+    Ok(tokens.join(" "))
+}
+
+impl Opt {
+    /// Parse the options on top of the defaults of the chat, `@name` expands to a preset.
+    pub fn parse(opt: &str, settings: &Settings) -> Result<Self, Error> {
+        let mut color = settings.color.unwrap_or(Color::Rgb([0xff, 0xff, 0xff]));
+        let mut align = settings.align;
+        let mut dry_run = false;
+        let mut show_detect = false;
+        let mut start = None;
+        let mut end = None;
+        let mut length = None;
+        let mut cover = None;
+        let mut frame = None;
+        let mut expire = None;
+
+        for x in expand_presets(opt, settings)?.split_whitespace() {
+            match x {
+                x if parse_align(x).is_some() => align = parse_align(x),
+                "d" | "dry" => dry_run = true,
+                "s" | "show" => {
+                    align = None;
+                    dry_run = true;
+                    show_detect = true;
+                }
+                x if x.contains('=') => {
+                    let (key, value) = x.split_once('=').unwrap();
+                    let valid = match key {
+                        "start" | "from" => parse_time(value).map(|x| start = Some(x)),
+                        "end" => parse_time(value).map(|x| end = Some(x)),
+                        "len" => parse_time(value).map(|x| length = Some(x)),
+                        "cover" => parse_time(value).map(|x| cover = Some(x)),
+                        "frame" => parse_frame(value).map(|x| frame = Some(x)),
+                        "until" => parse_datetime(value).map(|x| expire = Some(Expire::At(x))),
+                        "for" => parse_duration(value).map(|x| expire = Some(Expire::After(x))),
+                        "color" => parse_color(value).map(|x| color = x),
+                        "align" => parse_align(value).map(|x| align = Some(x)),
+                        _ => {
+                            return match suggest(key, OPTION_KEYS) {
+                                Some(x) => format!("未知的选项 {key}=, 是否想用 {x}=?").result(),
+                                None => format!("未知的选项 {key}=, 请使用 /help 查看可用的选项")
+                                    .result(),
+                            };
+                        }
+                    };
+                    if valid.is_none() {
+                        return format!("选项 {key}= 的值 {value} 无效").result();
+                    }
+                }
+                x => match parse_color(x) {
+                    Some(x) => color = x,
+                    None => {
+                        let keys = OPTION_KEYS.iter().map(|x| format!("{x}="));
+                        let options: Vec<_> = OPTION_FLAGS
+                            .iter()
+                            .map(|x| x.to_string())
+                            .chain(keys)
+                            .collect();
+                        return match suggest(x, &options) {
+                            Some(option) => format!("未知的选项 {x}, 是否想用 {option}?").result(),
+                            None => format!("未知的选项 {x}, 请使用 /help 查看可用的选项").result(),
+                        };
+                    }
+                },
+            }
+        }
+        if align.is_none() && !show_detect && settings.skip_detection {
+            align = Some(Align::Center);
+        }
+
+        Ok(Self {
+            color,
+            align,
+            dry_run,
+            show_detect,
+            start,
+            end,
+            length,
+            cover,
+            frame,
+            expire,
+        })
+    }
+}
+
+/// Levenshtein distance between two strings.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<_> = b.chars().collect();
+    let mut row: Vec<_> = (0..=b.len()).collect();
+    for (i, x) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, y) in b.iter().enumerate() {
+            let substitution = diagonal + usize::from(x != *y);
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+
+    row[b.len()]
+}
+
+/// The option closest to `token`, if it is close enough to be a typo of it.
+fn suggest<T: AsRef<str>>(token: &str, options: &[T]) -> Option<String> {
+    options
+        .iter()
+        .map(|x| (edit_distance(token, x.as_ref()), x.as_ref()))
+        .filter(|(distance, x)| *distance <= 2 && *distance < x.chars().count())
+        .min_by_key(|x| x.0)
+        .map(|x| x.1.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn time_in_seconds_or_clock_form() {
+        assert_eq!(parse_time("90"), Some(90.0));
+        assert_eq!(parse_time("1.5"), Some(1.5));
+        assert_eq!(parse_time("1:30"), Some(90.0));
+        assert_eq!(parse_time("1:00:05"), Some(3605.0));
+    }
+
+    #[test]
+    fn time_rejects_invalid_values() {
+        for x in ["", "1:", "-1", "inf", "NaN", "1m", "abc"] {
+            assert_eq!(parse_time(x), None, "{x}");
+        }
+    }
+
+    #[test]
+    fn frame_as_index_or_time() {
+        assert!(matches!(parse_frame("12"), Some(Frame::Index(12))));
+        assert!(matches!(parse_frame("1.5"), Some(Frame::Time(x)) if x == 1.5));
+        assert!(matches!(parse_frame("2s"), Some(Frame::Time(x)) if x == 2.0));
+        assert!(matches!(parse_frame("0:10"), Some(Frame::Time(x)) if x == 10.0));
+        assert!(parse_frame("-1").is_none());
+        assert!(parse_frame("s").is_none());
+    }
+
+    #[test]
+    fn duration_with_unit_suffix() {
+        assert_eq!(parse_duration("90"), Some(TimeDelta::seconds(90)));
+        assert_eq!(parse_duration("45s"), Some(TimeDelta::seconds(45)));
+        assert_eq!(parse_duration("1.5m"), Some(TimeDelta::seconds(90)));
+        assert_eq!(parse_duration("12h"), Some(TimeDelta::hours(12)));
+        assert_eq!(parse_duration("2d"), Some(TimeDelta::days(2)));
+        assert_eq!(parse_duration("1:30"), Some(TimeDelta::seconds(90)));
+    }
+
+    #[test]
+    fn duration_rejects_invalid_values() {
+        for x in ["", "h", "-1h", "1w", "1hh", "infd"] {
+            assert_eq!(parse_duration(x), None, "{x}");
+        }
+    }
+
+    fn presets() -> Settings {
+        Settings {
+            presets: [("pink", "t ffc0cb"), ("clip", "start=1 len=5")]
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn presets_expand_in_place() {
+        let settings = presets();
+        let options = expand_presets("@pink  @clip d", &settings).unwrap();
+        assert_eq!(options, "t ffc0cb start=1 len=5 d");
+        assert_eq!(expand_presets("c", &settings).unwrap(), "c");
+
+        let e = expand_presets("@blue", &settings).unwrap_err();
+        assert_eq!(e.to_string(), "没有名为 @blue 的预设");
+    }
+
+    #[test]
+    fn options_in_any_order() {
+        let opt = Opt::parse(
+            "cover=2 t #ffc0cb start=1 len=5 frame=10 for=1h d",
+            &Settings::default(),
+        )
+        .unwrap();
+        assert!(matches!(opt.align, Some(Align::Top)));
+        assert!(matches!(opt.color, Color::Rgb([0xff, 0xc0, 0xcb])));
+        assert_eq!(
+            (opt.start, opt.end, opt.length),
+            (Some(1.0), None, Some(5.0))
+        );
+        assert_eq!(opt.cover, Some(2.0));
+        assert!(matches!(opt.frame, Some(Frame::Index(10))));
+        assert!(matches!(opt.expire, Some(Expire::After(x)) if x == TimeDelta::hours(1)));
+        assert!(opt.dry_run && !opt.show_detect);
+
+        let opt = Opt::parse("color=tr align=l", &Settings::default()).unwrap();
+        assert!(matches!(opt.color, Color::Trans));
+        assert!(matches!(opt.align, Some(Align::Left)));
+    }
+
+    #[test]
+    fn options_on_top_of_chat_defaults() {
+        let settings = Settings {
+            color: Some(Color::Trans),
+            skip_detection: true,
+            ..Default::default()
+        };
+        let opt = Opt::parse("", &settings).unwrap();
+        assert!(matches!(opt.color, Color::Trans));
+        assert!(matches!(opt.align, Some(Align::Center)));
+
+        let opt = Opt::parse("000000 b", &settings).unwrap();
+        assert!(matches!(opt.color, Color::Rgb([0, 0, 0])));
+        assert!(matches!(opt.align, Some(Align::Bottom)));
+
+        let opt = Opt::parse("s", &settings).unwrap();
+        assert!(opt.align.is_none() && opt.dry_run && opt.show_detect);
+    }
+
+    #[test]
+    fn invalid_options_are_reported() {
+        let error = |x: &str| Opt::parse(x, &Settings::default()).unwrap_err().to_string();
+        assert_eq!(error("cneter"), "未知的选项 cneter, 是否想用 center?");
+        assert_eq!(error("strat=1"), "未知的选项 strat=, 是否想用 start=?");
+        assert_eq!(error("start=abc"), "选项 start= 的值 abc 无效");
+        assert_eq!(
+            error("t xyzzy"),
+            "未知的选项 xyzzy, 请使用 /help 查看可用的选项"
+        );
+        assert_eq!(
+            error("1000000"),
+            "未知的选项 1000000, 请使用 /help 查看可用的选项"
+        );
+        assert_eq!(error("ce"), "未知的选项 ce, 请使用 /help 查看可用的选项");
+        assert_eq!(error("dd"), "未知的选项 dd, 是否想用 dry?");
+    }
+
+    #[test]
+    fn colors_need_3_or_6_hex_digits() {
+        assert!(matches!(
+            parse_color("fff"),
+            Some(Color::Rgb([0xff, 0xff, 0xff]))
+        ));
+        assert!(matches!(
+            parse_color("#c0c"),
+            Some(Color::Rgb([0xcc, 0x00, 0xcc]))
+        ));
+        assert!(matches!(
+            parse_color("ffc0cb"),
+            Some(Color::Rgb([0xff, 0xc0, 0xcb]))
+        ));
+        assert!(matches!(
+            parse_color("#000000"),
+            Some(Color::Rgb([0, 0, 0]))
+        ));
+        assert!(matches!(parse_color("tr"), Some(Color::Trans)));
+        for x in [
+            "", "#", "ce", "dd", "#ce", "ffff", "1000000", "+fff", "fffgff",
+        ] {
+            assert!(parse_color(x).is_none(), "{x}");
+        }
+    }
+
+    #[test]
+    fn edit_distance_counts_chars() {
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("", "abc"), 3);
+        assert_eq!(edit_distance("abc", "abc"), 0);
+        assert_eq!(edit_distance("头像", "头象"), 1);
+    }
+
+    #[test]
+    fn suggest_only_close_options() {
+        assert_eq!(suggest("cneter", OPTION_FLAGS).as_deref(), Some("center"));
+        assert_eq!(suggest("lenn", OPTION_KEYS).as_deref(), Some("len"));
+        assert_eq!(suggest("q", OPTION_FLAGS), None);
+        assert_eq!(suggest("xyzzy", OPTION_FLAGS), None);
+    }
+}
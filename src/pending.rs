@@ -0,0 +1,282 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Mutex, MutexGuard};
+
+use chrono::{DateTime, Utc};
+use lazy_static::lazy_static;
+use tokio::sync::oneshot::{self, Receiver, Sender};
+
+use crate::command::Avatar;
+use crate::error::{Error, IntoErrorMessage};
+use crate::history::Source;
+use crate::opt::Expire;
+
+lazy_static! {
+    /// Avatars waiting for approval, keyed by chat id and the id of the message with the buttons.
+    static ref PENDING: Mutex<HashMap<(i64, i32), Pending>> = Mutex::new(HashMap::new());
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Kind {
+    /// Approved by enough votes from any member.
+    Vote,
+    /// Approved or rejected by one admin.
+    Approval,
+    /// A dry run result, applied with the button on it.
+    Preview,
+}
+
+#[derive(Clone, Copy)]
+pub enum Decision {
+    Approved,
+    Rejected,
+    /// Closed by an admin before it was decided.
+    Canceled,
+}
+
+/// Previews kept for each chat, the oldest ones are dropped first.
+const MAX_PREVIEWS: usize = 5;
+
+pub struct Pending {
+    pub kind: Kind,
+    pub avatar: Avatar,
+    pub source: Source,
+    pub restore_time: Option<DateTime<Utc>>,
+    /// Of previews, turned into the restore time once applied.
+    pub expire: Option<Expire>,
+    pub threshold: usize,
+    /// Members who can vote, if known, to end a vote once it can no longer pass.
+    voters: Option<usize>,
+    approvals: HashSet<i64>,
+    rejections: HashSet<i64>,
+    decided: Option<Sender<Decision>>,
+}
+
+impl Pending {
+    fn decision(&self) -> Option<Decision> {
+        let rejected = self.rejections.len();
+        if self.approvals.len() >= self.threshold {
+            Some(Decision::Approved)
+        } else if rejected >= self.threshold
+            // too few members left who have not rejected it
+            || self.voters.is_some_and(|x| x.saturating_sub(rejected) < self.threshold)
+        {
+            Some(Decision::Rejected)
+        } else {
+            None
+        }
+    }
+}
+
+pub struct Tally {
+    pub approvals: usize,
+    pub rejections: usize,
+    pub threshold: usize,
+    /// Whether the vote has ended.
+    pub decided: bool,
+}
+
+fn lock() -> MutexGuard<'static, HashMap<(i64, i32), Pending>> {
+    PENDING.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// The returned receiver resolves once the avatar is approved, rejected or canceled.
+#[allow(clippy::too_many_arguments)]
+pub fn insert(
+    chat_id: i64,
+    message_id: i32,
+    kind: Kind,
+    avatar: Avatar,
+    source: Source,
+    restore_time: Option<DateTime<Utc>>,
+    threshold: usize,
+    voters: Option<usize>,
+) -> Receiver<Decision> {
+    let (sender, receiver) = oneshot::channel();
+    let pending = Pending {
+        kind,
+        avatar,
+        source,
+        restore_time,
+        expire: None,
+        threshold,
+        voters,
+        approvals: HashSet::new(),
+        rejections: HashSet::new(),
+        decided: Some(sender),
+    };
+    lock().insert((chat_id, message_id), pending);
+    receiver
+}
+
+pub fn remove(chat_id: i64, message_id: i32) -> Option<Pending> {
+    lock().remove(&(chat_id, message_id))
+}
+
+pub fn insert_preview(
+    chat_id: i64,
+    message_id: i32,
+    avatar: Avatar,
+    source: Source,
+    expire: Option<Expire>,
+) {
+    let preview = Pending {
+        kind: Kind::Preview,
+        avatar,
+        source,
+        restore_time: None,
+        expire,
+        threshold: 0,
+        voters: None,
+        approvals: HashSet::new(),
+        rejections: HashSet::new(),
+        decided: None,
+    };
+
+    let mut pending = lock();
+    let mut previews: Vec<_> = pending
+        .iter()
+        .filter(|(k, v)| k.0 == chat_id && v.kind == Kind::Preview)
+        .map(|(k, _)| *k)
+        .collect();
+    // message ids grow within a chat
+    previews.sort();
+    for i in previews.iter().rev().skip(MAX_PREVIEWS - 1) {
+        pending.remove(i);
+    }
+    pending.insert((chat_id, message_id), preview);
+}
+
+pub fn take_preview(chat_id: i64, message_id: i32) -> Option<Pending> {
+    let mut pending = lock();
+    let key = (chat_id, message_id);
+    match pending.get(&key) {
+        Some(x) if x.kind == Kind::Preview => pending.remove(&key),
+        _ => None,
+    }
+}
+
+/// Whether the chat has an avatar waiting for votes or approval.
+pub fn is_pending(chat_id: i64) -> bool {
+    lock()
+        .iter()
+        .any(|(k, v)| k.0 == chat_id && v.kind != Kind::Preview)
+}
+
+pub fn vote(chat_id: i64, message_id: i32, user_id: i64, approve: bool) -> Result<Tally, Error> {
+    let mut pending = lock();
+    let x = pending
+        .get_mut(&(chat_id, message_id))
+        .filter(|x| x.kind == Kind::Vote)
+        .ok_or("投票已结束".error())?;
+    if x.source.sender_id == Some(user_id) {
+        return "不能为自己的提议投票".result();
+    }
+    if approve {
+        x.rejections.remove(&user_id);
+        x.approvals.insert(user_id);
+    } else {
+        x.approvals.remove(&user_id);
+        x.rejections.insert(user_id);
+    }
+
+    let decision = x.decision();
+    if let Some(decision) = decision {
+        if let Some(decided) = x.decided.take() {
+            let _ = decided.send(decision);
+        }
+    }
+
+    Ok(Tally {
+        approvals: x.approvals.len(),
+        rejections: x.rejections.len(),
+        threshold: x.threshold,
+        decided: decision.is_some(),
+    })
+}
+
+/// Returns `false` if there is no such pending avatar or it has been decided.
+pub fn decide(chat_id: i64, message_id: i32, kind: Kind, decision: Decision) -> bool {
+    let mut pending = lock();
+    let decided = pending
+        .get_mut(&(chat_id, message_id))
+        .filter(|x| x.kind == kind)
+        .and_then(|x| x.decided.take());
+
+    match decided {
+        Some(x) => x.send(decision).is_ok(),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn insert_vote(chat_id: i64, threshold: usize, voters: Option<usize>) -> Receiver<Decision> {
+        let avatar = Avatar {
+            data: Vec::new(),
+            is_video: false,
+            video_start_ts: None,
+            uploaded: None,
+        };
+        let source = Source {
+            message_id: None,
+            sender: None,
+            sender_id: Some(1),
+            command: String::new(),
+            options: String::new(),
+        };
+        insert(
+            chat_id,
+            1,
+            Kind::Vote,
+            avatar,
+            source,
+            None,
+            threshold,
+            voters,
+        )
+    }
+
+    #[test]
+    fn votes_pass_with_enough_approvals() {
+        let mut decided = insert_vote(-1, 2, None);
+        let e = vote(-1, 1, 1, true).err().unwrap();
+        assert_eq!(e.to_string(), "不能为自己的提议投票");
+        assert!(!vote(-1, 1, 2, true).unwrap().decided);
+        // a changed vote is counted once
+        assert!(!vote(-1, 1, 2, false).unwrap().decided);
+        assert!(!vote(-1, 1, 2, true).unwrap().decided);
+        assert!(decided.try_recv().is_err());
+
+        let tally = vote(-1, 1, 3, true).unwrap();
+        assert!(tally.decided && tally.approvals == 2 && tally.rejections == 0);
+        assert!(matches!(decided.try_recv(), Ok(Decision::Approved)));
+    }
+
+    #[test]
+    fn votes_fail_once_rejections_reach_the_threshold() {
+        let mut decided = insert_vote(-2, 2, None);
+        assert!(!vote(-2, 1, 2, false).unwrap().decided);
+        assert!(vote(-2, 1, 3, false).unwrap().decided);
+        assert!(matches!(decided.try_recv(), Ok(Decision::Rejected)));
+    }
+
+    #[test]
+    fn votes_fail_once_approval_is_out_of_reach() {
+        let mut decided = insert_vote(-3, 3, Some(4));
+        assert!(!vote(-3, 1, 2, true).unwrap().decided);
+        assert!(!vote(-3, 1, 3, false).unwrap().decided);
+        assert!(vote(-3, 1, 4, false).unwrap().decided);
+        assert!(matches!(decided.try_recv(), Ok(Decision::Rejected)));
+    }
+
+    #[test]
+    fn votes_can_be_canceled_once() {
+        let mut decided = insert_vote(-4, 2, None);
+        assert!(!decide(-4, 1, Kind::Approval, Decision::Canceled));
+        assert!(decide(-4, 1, Kind::Vote, Decision::Canceled));
+        assert!(!decide(-4, 1, Kind::Vote, Decision::Canceled));
+        assert!(matches!(decided.try_recv(), Ok(Decision::Canceled)));
+    }
+}
@@ -0,0 +1,194 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use chrono::{DateTime, Local, TimeDelta, Utc};
+use cron::Schedule;
+use grammers_client::types::PackedChat;
+use lazy_static::lazy_static;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::chats;
+use crate::error::Error;
+use crate::history::Source;
+use crate::opt::parse_duration;
+use crate::store::Store;
+
+/// Cron fire times checked for the shortest gap between two rotations.
+const CRON_SAMPLES: usize = 64;
+
+lazy_static! {
+    static ref POOLS: Store<HashMap<i64, Pool>> = Store::open("pool.json");
+}
+
+/// Load the avatar pools from disk.
+pub fn load() {
+    lazy_static::initialize(&POOLS);
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Item {
+    pub options: String,
+    pub source: Source,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Pool {
+    chat: String,
+    pub items: Vec<Item>,
+    /// An interval such as `12h`, or a cron expression.
+    pub rotation: Option<String>,
+    pub random: bool,
+    pub next_index: usize,
+    pub next_time: Option<DateTime<Utc>>,
+}
+
+/// Five fields are taken as a standard cron expression without seconds.
+fn cron_schedule(rotation: &str) -> Option<Schedule> {
+    let expression = match rotation.split_whitespace().count() {
+        5 => format!("0 {rotation}"),
+        _ => rotation.into(),
+    };
+    Schedule::from_str(&expression).ok()
+}
+
+/// The next rotation time after now, `None` if `rotation` is neither an interval nor a cron expression.
+pub fn next_rotation(rotation: &str) -> Option<DateTime<Utc>> {
+    if let Some(x) = parse_duration(rotation) {
+        return Some(Utc::now() + x);
+    }
+
+    let next = cron_schedule(rotation)?.after(&Local::now()).next()?;
+    Some(next.with_timezone(&Utc))
+}
+
+/// The shortest gap between two rotations, `None` if there are less than two rotations.
+pub fn min_interval(rotation: &str) -> Option<TimeDelta> {
+    if let Some(x) = parse_duration(rotation) {
+        return Some(x);
+    }
+
+    let times: Vec<_> = cron_schedule(rotation)?
+        .after(&Local::now())
+        .take(CRON_SAMPLES)
+        .collect();
+    times.windows(2).map(|x| x[1] - x[0]).min()
+}
+
+fn update<R>(chat: PackedChat, f: impl FnOnce(&mut Pool) -> R) -> Result<R, Error> {
+    POOLS.update(|x| {
+        let pool = x.entry(chat.id).or_default();
+        pool.chat = chat.to_hex();
+        f(pool)
+    })
+}
+
+pub fn get(chat_id: i64) -> Pool {
+    POOLS.read(|x| x.get(&chat_id).cloned().unwrap_or_default())
+}
+
+/// Returns the number of items in the pool.
+pub fn add(chat: PackedChat, item: Item) -> Result<usize, Error> {
+    update(chat, |x| {
+        x.items.push(item);
+        x.items.len()
+    })
+}
+
+pub fn remove(chat: PackedChat, index: usize) -> Result<Option<Item>, Error> {
+    update(chat, |x| {
+        (index < x.items.len()).then(|| x.items.remove(index))
+    })
+}
+
+/// Remove an item returned by [`take_due`], which may have moved since.
+pub fn discard(chat_id: i64, item: &Item) -> Result<(), Error> {
+    POOLS.update(|x| {
+        if let Some(pool) = x.get_mut(&chat_id) {
+            let position = pool.items.iter().position(|x| {
+                x.options == item.options && x.source.message_id == item.source.message_id
+            });
+            if let Some(i) = position {
+                pool.items.remove(i);
+            }
+        }
+    })
+}
+
+pub fn set_rotation(
+    chat: PackedChat,
+    rotation: Option<String>,
+) -> Result<Option<DateTime<Utc>>, Error> {
+    update(chat, |x| {
+        x.next_time = rotation.as_deref().and_then(next_rotation);
+        x.rotation = rotation;
+        x.next_time
+    })
+}
+
+pub fn set_random(chat: PackedChat, random: bool) -> Result<(), Error> {
+    update(chat, |x| x.random = random)
+}
+
+/// Pick the items of all pools that are due and advance their rotation.
+/// Pools of chats no longer served are paused until the chat is allowed again.
+pub fn take_due() -> Result<Vec<(PackedChat, Item)>, Error> {
+    let now = Utc::now();
+    let is_due = |chat_id: i64, x: &Pool| {
+        !x.items.is_empty() && x.next_time.is_some_and(|x| x <= now) && chats::is_allowed(chat_id)
+    };
+    if !POOLS.read(|x| x.iter().any(|(&k, v)| is_due(k, v))) {
+        return Ok(Vec::new());
+    }
+
+    POOLS.update(|x| {
+        let mut due = Vec::new();
+        for (_, pool) in x.iter_mut().filter(|(&k, v)| is_due(k, v)) {
+            let index = if pool.random {
+                rand::thread_rng().gen_range(0..pool.items.len())
+            } else {
+                pool.next_index % pool.items.len()
+            };
+            pool.next_index = index + 1;
+            pool.next_time = pool.rotation.as_deref().and_then(next_rotation);
+
+            match PackedChat::from_hex(&pool.chat) {
+                Ok(chat) => due.push((chat, pool.items[index].clone())),
+                Err(_) => println!("Invalid packed chat in pool: {}", pool.chat),
+            }
+        }
+        due
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotation_by_interval() {
+        let now = Utc::now();
+        let next = next_rotation("30m").unwrap();
+        assert!(next >= now + TimeDelta::minutes(30));
+        assert!(next <= Utc::now() + TimeDelta::minutes(30));
+        assert_eq!(min_interval("12h"), Some(TimeDelta::hours(12)));
+    }
+
+    #[test]
+    fn rotation_by_cron_expression() {
+        let now = Utc::now();
+        let next = next_rotation("*/10 * * * *").unwrap();
+        assert!(next > now && next <= Utc::now() + TimeDelta::minutes(10));
+        assert_eq!(min_interval("*/10 * * * *"), Some(TimeDelta::minutes(10)));
+        assert_eq!(min_interval("0 9,17 * * *"), Some(TimeDelta::hours(8)));
+        assert_eq!(min_interval("*/20 * * * * *"), Some(TimeDelta::seconds(20)));
+    }
+
+    #[test]
+    fn invalid_rotation() {
+        for x in ["", "soon", "* * *", "61 * * * *"] {
+            assert_eq!(next_rotation(x), None, "{x}");
+            assert_eq!(min_interval(x), None, "{x}");
+        }
+    }
+}
@@ -0,0 +1,251 @@
+use chrono::{DateTime, TimeDelta, Utc};
+use grammers_client::types::PackedChat;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+
+use crate::chats;
+use crate::error::Error;
+use crate::history::Source;
+use crate::store::{AvatarFile, Store};
+
+/// Attempts of a job before it is given up.
+pub const MAX_ATTEMPTS: u32 = 8;
+/// Minutes before the first retry, doubled after each failed attempt.
+const RETRY_DELAY: i64 = 5;
+
+lazy_static! {
+    static ref JOBS: Store<Jobs> = Store::open("schedule.json");
+}
+
+/// Load the scheduled jobs from disk.
+pub fn load() {
+    lazy_static::initialize(&JOBS);
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct Jobs {
+    next_id: u64,
+    jobs: Vec<Job>,
+}
+
+impl Jobs {
+    fn take_due(&mut self, now: DateTime<Utc>, is_allowed: impl Fn(i64) -> bool) -> Vec<Job> {
+        let mut due = Vec::new();
+        for job in self.jobs.iter_mut() {
+            if job.time > now || !is_allowed(job.chat_id) {
+                continue;
+            }
+
+            job.attempts += 1;
+            let delay = RETRY_DELAY << job.attempts.min(MAX_ATTEMPTS).saturating_sub(1);
+            job.time = now + TimeDelta::minutes(delay);
+            due.push(job.clone());
+        }
+        due
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Action {
+    /// Restore the avatar saved before a temporary one, `None` means the chat had no avatar.
+    Restore { avatar: Option<AvatarFile> },
+    /// Run `/set_avatar` with `options` on the message `source.message_id`.
+    SetAvatar { options: String, source: Source },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Job {
+    pub id: u64,
+    pub chat_id: i64,
+    chat: String,
+    pub time: DateTime<Utc>,
+    pub action: Action,
+    /// Attempts made so far, including a running one.
+    #[serde(default)]
+    pub attempts: u32,
+}
+
+impl Job {
+    pub fn chat(&self) -> Result<PackedChat, Error> {
+        Ok(PackedChat::from_hex(&self.chat).map_err(|_| "Invalid packed chat")?)
+    }
+
+    fn is_restore_of(&self, chat_id: i64) -> bool {
+        self.chat_id == chat_id && matches!(self.action, Action::Restore { .. })
+    }
+
+    pub fn cleanup(&self) {
+        match &self.action {
+            Action::Restore { avatar } => {
+                if let Some(x) = avatar {
+                    x.remove()
+                }
+            }
+            Action::SetAvatar { .. } => {}
+        }
+    }
+}
+
+pub fn add(chat: PackedChat, time: DateTime<Utc>, action: Action) -> Result<u64, Error> {
+    JOBS.update(|x| {
+        x.next_id += 1;
+        x.jobs.push(Job {
+            id: x.next_id,
+            chat_id: chat.id,
+            chat: chat.to_hex(),
+            time,
+            action,
+            attempts: 0,
+        });
+        x.next_id
+    })
+}
+
+/// Pending jobs of the chat, ordered by time.
+pub fn jobs(chat_id: i64) -> Vec<Job> {
+    let mut jobs = JOBS.read(|x| {
+        x.jobs
+            .iter()
+            .filter(|x| x.chat_id == chat_id)
+            .cloned()
+            .collect::<Vec<_>>()
+    });
+    jobs.sort_by_key(|x| x.time);
+    jobs
+}
+
+/// Returns `false` if the chat has no job with the id.
+pub fn cancel(chat_id: i64, id: u64) -> Result<bool, Error> {
+    let canceled = JOBS.update(|x| {
+        let index = x
+            .jobs
+            .iter()
+            .position(|x| x.chat_id == chat_id && x.id == id);
+        index.map(|i| x.jobs.remove(i))
+    })?;
+
+    match canceled {
+        Some(x) => {
+            x.cleanup();
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+pub fn has_restore(chat_id: i64) -> bool {
+    JOBS.read(|x| x.jobs.iter().any(|x| x.is_restore_of(chat_id)))
+}
+
+pub fn reschedule_restore(chat_id: i64, time: DateTime<Utc>) -> Result<(), Error> {
+    JOBS.update(|x| {
+        for i in x.jobs.iter_mut().filter(|x| x.is_restore_of(chat_id)) {
+            i.time = time;
+        }
+    })
+}
+
+pub fn cancel_restore(chat_id: i64) -> Result<(), Error> {
+    let canceled = JOBS.update(|x| {
+        let (canceled, jobs): (Vec<_>, _) =
+            x.jobs.drain(..).partition(|x| x.is_restore_of(chat_id));
+        x.jobs = jobs;
+        canceled
+    })?;
+    for i in canceled {
+        i.cleanup();
+    }
+
+    Ok(())
+}
+
+/// Return all jobs that are due and push each back by the retry delay, so a job stays
+/// stored until [`finish`] is called and runs again if it fails or the bot stops meanwhile.
+/// Jobs of chats no longer served are kept untouched until the chat is allowed again.
+pub fn take_due() -> Result<Vec<Job>, Error> {
+    let now = Utc::now();
+    let is_due = |x: &Job| x.time <= now && chats::is_allowed(x.chat_id);
+    if !JOBS.read(|x| x.jobs.iter().any(is_due)) {
+        return Ok(Vec::new());
+    }
+
+    JOBS.update(|x| x.take_due(now, chats::is_allowed))
+}
+
+/// Remove a job that succeeded or ran out of attempts.
+pub fn finish(job: &Job) -> Result<(), Error> {
+    cancel(job.chat_id, job.id)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job(id: u64, chat_id: i64, time: DateTime<Utc>) -> Job {
+        Job {
+            id,
+            chat_id,
+            chat: String::new(),
+            time,
+            action: Action::Restore { avatar: None },
+            attempts: 0,
+        }
+    }
+
+    #[test]
+    fn due_jobs_are_kept_and_pushed_back() {
+        let now = Utc::now();
+        let mut jobs = Jobs {
+            next_id: 3,
+            jobs: vec![job(1, 1, now), job(2, 1, now + TimeDelta::minutes(1))],
+        };
+
+        let due = jobs.take_due(now, |_| true);
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].id, 1);
+        assert_eq!(due[0].attempts, 1);
+        assert_eq!(jobs.jobs.len(), 2);
+        assert_eq!(jobs.jobs[0].time, now + TimeDelta::minutes(RETRY_DELAY));
+        assert_eq!(jobs.jobs[1].attempts, 0);
+    }
+
+    #[test]
+    fn retry_delay_doubles_up_to_the_last_attempt() {
+        let mut now = Utc::now();
+        let mut jobs = Jobs {
+            next_id: 2,
+            jobs: vec![job(1, 1, now)],
+        };
+
+        let mut delays = Vec::new();
+        for _ in 0..MAX_ATTEMPTS + 2 {
+            let due = jobs.take_due(now, |_| true);
+            assert_eq!(due.len(), 1);
+            delays.push((due[0].time - now).num_minutes());
+            now = due[0].time;
+        }
+
+        assert_eq!(delays[..4], [5, 10, 20, 40]);
+        let last = RETRY_DELAY << (MAX_ATTEMPTS - 1);
+        assert!(delays[MAX_ATTEMPTS as usize - 1..]
+            .iter()
+            .all(|x| *x == last));
+    }
+
+    #[test]
+    fn jobs_of_denied_chats_are_left_alone() {
+        let now = Utc::now();
+        let mut jobs = Jobs {
+            next_id: 3,
+            jobs: vec![job(1, 1, now), job(2, 2, now)],
+        };
+
+        let due = jobs.take_due(now, |x| x == 2);
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].id, 2);
+        assert_eq!(jobs.jobs[0].time, now);
+        assert_eq!(jobs.jobs[0].attempts, 0);
+    }
+}
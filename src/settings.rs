@@ -0,0 +1,120 @@
+use std::collections::BTreeMap;
+use std::fmt::{self, Display, Formatter};
+
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+
+use crate::error::Error;
+use crate::opt::{Align, Color};
+use crate::store::ChatStore;
+
+lazy_static! {
+    static ref SETTINGS: ChatStore<Settings> = ChatStore::open("settings.json");
+}
+
+/// Load the chat settings from disk.
+pub fn load() {
+    lazy_static::initialize(&SETTINGS);
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Threshold {
+    Count(usize),
+    Fraction(f64),
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct Vote {
+    pub threshold: Threshold,
+    /// Seconds to wait for approvals.
+    pub window: u64,
+}
+
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Policy {
+    Admins,
+    #[default]
+    Anyone,
+    /// Anyone, but requests of non-admins need to be approved by an admin.
+    Approval,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Settings {
+    pub vote: Option<Vote>,
+    #[serde(default)]
+    pub policy: Policy,
+    /// Seconds between two avatar updates, `None` for the global default.
+    #[serde(default)]
+    pub cooldown: Option<u64>,
+    #[serde(default)]
+    pub color: Option<Color>,
+    #[serde(default)]
+    pub align: Option<Align>,
+    #[serde(default)]
+    pub skip_detection: bool,
+    /// Options of `/set_avatar` saved by name, used as `@name`.
+    #[serde(default)]
+    pub presets: BTreeMap<String, String>,
+}
+
+impl Threshold {
+    pub fn votes(&self, member_count: usize) -> usize {
+        match *self {
+            Self::Count(x) => x,
+            Self::Fraction(x) => (x * member_count as f64).ceil() as _,
+        }
+        .max(1)
+    }
+}
+
+impl Display for Threshold {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Count(x) => write!(f, "{x} 票"),
+            // rounded to two decimals to hide the noise of `x / 100.0`
+            Self::Fraction(x) => write!(f, "{}% 成员", (x * 10000.0).round() / 100.0),
+        }
+    }
+}
+
+impl Display for Policy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Admins => write!(f, "仅管理员"),
+            Self::Anyone => write!(f, "所有人"),
+            Self::Approval => write!(f, "所有人, 非管理员需管理员审批"),
+        }
+    }
+}
+
+pub fn get(chat_id: i64) -> Settings {
+    SETTINGS.get(chat_id)
+}
+
+pub fn update<R>(chat_id: i64, f: impl FnOnce(&mut Settings) -> R) -> Result<R, Error> {
+    SETTINGS.update(chat_id, f)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn votes_needed() {
+        assert_eq!(Threshold::Count(3).votes(100), 3);
+        assert_eq!(Threshold::Fraction(0.5).votes(7), 4);
+        assert_eq!(Threshold::Fraction(0.01).votes(10), 1);
+        assert_eq!(Threshold::Fraction(0.5).votes(0), 1);
+    }
+
+    #[test]
+    fn threshold_display() {
+        assert_eq!(Threshold::Count(3).to_string(), "3 票");
+        assert_eq!(Threshold::Fraction(0.5).to_string(), "50% 成员");
+        assert_eq!(Threshold::Fraction(7.0 / 100.0).to_string(), "7% 成员");
+        assert_eq!(Threshold::Fraction(33.5 / 100.0).to_string(), "33.5% 成员");
+    }
+}
@@ -0,0 +1,29 @@
+use chrono::{DateTime, Utc};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+
+use crate::error::Error;
+use crate::store::ChatStore;
+
+lazy_static! {
+    static ref STATE: ChatStore<State> = ChatStore::open("state.json");
+}
+
+/// Load the chat state from disk.
+pub fn load() {
+    lazy_static::initialize(&STATE);
+}
+
+/// Runtime state of a chat that should survive restarts.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct State {
+    pub last_update: Option<DateTime<Utc>>,
+}
+
+pub fn get(chat_id: i64) -> State {
+    STATE.get(chat_id)
+}
+
+pub fn update<R>(chat_id: i64, f: impl FnOnce(&mut State) -> R) -> Result<R, Error> {
+    STATE.update(chat_id, f)
+}
@@ -0,0 +1,120 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::sync::{Mutex, MutexGuard};
+
+use chrono::Utc;
+use lazy_static::lazy_static;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::command::Avatar;
+use crate::error::{Error, IntoErrorMessage};
+
+lazy_static! {
+    pub static ref DATA_DIR: PathBuf = {
+        let data_dir = PathBuf::from(env::var("DATA_DIR").unwrap_or("state".into()));
+        fs::create_dir_all(&data_dir).expect("Creating DATA_DIR failed");
+        data_dir
+    };
+}
+
+pub struct Store<T> {
+    path: PathBuf,
+    data: Mutex<T>,
+}
+
+impl<T: Serialize + DeserializeOwned + Default> Store<T> {
+    pub fn open(name: &str) -> Self {
+        let path = DATA_DIR.join(name);
+        let data = match fs::read(&path) {
+            Ok(x) => serde_json::from_slice(&x)
+                .unwrap_or_else(|e| panic!("Parsing {} failed: {e}", path.display())),
+            Err(e) if e.kind() == ErrorKind::NotFound => T::default(),
+            Err(e) => panic!("Reading {} failed: {e}", path.display()),
+        };
+
+        Self {
+            path,
+            data: Mutex::new(data),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, T> {
+        self.data.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn read<R>(&self, f: impl FnOnce(&T) -> R) -> R {
+        f(&self.lock())
+    }
+
+    pub fn update<R>(&self, f: impl FnOnce(&mut T) -> R) -> Result<R, Error> {
+        let mut data = self.lock();
+        let ret = f(&mut data);
+
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec_pretty(&*data)?)?;
+        fs::rename(tmp, &self.path)?;
+        Ok(ret)
+    }
+}
+
+/// A store holding one `T` for each chat.
+pub struct ChatStore<T>(Store<HashMap<i64, T>>);
+
+impl<T: Serialize + DeserializeOwned + Default + Clone> ChatStore<T> {
+    pub fn open(name: &str) -> Self {
+        Self(Store::open(name))
+    }
+
+    pub fn get(&self, chat_id: i64) -> T {
+        self.0
+            .read(|x| x.get(&chat_id).cloned().unwrap_or_default())
+    }
+
+    pub fn update<R>(&self, chat_id: i64, f: impl FnOnce(&mut T) -> R) -> Result<R, Error> {
+        self.0.update(|x| f(x.entry(chat_id).or_default()))
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AvatarFile {
+    pub file: String,
+    pub is_video: bool,
+    pub video_start_ts: Option<f64>,
+}
+
+impl AvatarFile {
+    pub fn save(dir: &str, avatar: &Avatar) -> Result<Self, Error> {
+        fs::create_dir_all(DATA_DIR.join(dir))?;
+        let file = format!(
+            "{dir}/{}.{}",
+            Utc::now().timestamp_millis(),
+            avatar.extension()
+        );
+        fs::write(DATA_DIR.join(&file), &avatar.data)?;
+
+        Ok(Self {
+            file,
+            is_video: avatar.is_video,
+            video_start_ts: avatar.video_start_ts,
+        })
+    }
+
+    pub fn load(&self) -> Result<Avatar, Error> {
+        let data = fs::read(DATA_DIR.join(&self.file)).or("头像文件已丢失".result())?;
+
+        Ok(Avatar {
+            data,
+            is_video: self.is_video,
+            video_start_ts: self.video_start_ts,
+            uploaded: None,
+        })
+    }
+
+    pub fn remove(&self) {
+        let _ = fs::remove_file(DATA_DIR.join(&self.file));
+    }
+}
@@ -1,9 +1,14 @@
-use std::cmp::min;
+use std::cmp::{max, min};
+use std::env;
+use std::ffi::CString;
 use std::io::{Cursor, Read, Seek, SeekFrom, Write};
 use std::slice;
+use std::str::FromStr;
 use std::sync::{Arc, Mutex};
 
 use flate2::write::GzDecoder;
+use image::math::Rect;
+use lazy_static::lazy_static;
 use rlottie::{Animation, Surface};
 use rsmpeg::avcodec::{AVCodec, AVCodecContext};
 use rsmpeg::avformat::{
@@ -14,9 +19,88 @@ use rsmpeg::error::RsmpegError;
 use rsmpeg::ffi;
 use rsmpeg::swscale::SwsContext;
 
-use crate::command::Color;
-use crate::error::Error;
-use crate::image::{set_color, trans_flag};
+use crate::error::{Error, IntoErrorMessage};
+use crate::ffmpeg::{frame_to_image, seconds_to_ts, seek_frame, stream_rotation};
+use crate::image::{detect_faces, rotate_image, select_face, set_color, square_rect, trans_flag};
+use crate::opt::{Align, Color, Opt};
+
+const MAX_DURATION: f64 = 10.0;
+const MIN_VIDEO_SIZE: i32 = 160;
+const MIN_BIT_RATE: i64 = 64 * 1000;
+const MAX_ENCODE_ATTEMPTS: usize = 3;
+const TRACK_INTERVAL: f64 = 0.5;
+const TRACK_SMOOTHING: usize = 2;
+const DEFAULT_ENCODERS: &[(&str, &[(&str, &str)])] = &[
+    ("libx264", &[("preset", "slow")]),
+    ("libopenh264", &[("b", "1M")]),
+    ("mpeg4", &[("b", "1M")]),
+];
+
+struct Encoder {
+    name: CString,
+    options: Vec<(CString, CString)>,
+}
+
+lazy_static! {
+    static ref MAX_VIDEO_SIZE: i32 = match env::var("MAX_VIDEO_SIZE") {
+        // Rounded down to even, so that the scaled sides never exceed it.
+        Ok(x) => i32::from_str(&x)
+            .ok()
+            .filter(|x| *x >= MIN_VIDEO_SIZE)
+            .map(|x| x - x % 2)
+            .unwrap_or_else(|| {
+                panic!("MAX_VIDEO_SIZE must be a number of at least {MIN_VIDEO_SIZE}")
+            }),
+        Err(_) => 800,
+    };
+    static ref MAX_VIDEO_FILESIZE: usize = match env::var("MAX_VIDEO_FILESIZE") {
+        Ok(x) => usize::from_str(&x).expect("Parsing MAX_VIDEO_FILESIZE failed"),
+        Err(_) => 2 * 1024 * 1024,
+    };
+    static ref VIDEO_ENCODER: Option<Encoder> = {
+        let list = env::var("VIDEO_ENCODERS").unwrap_or_else(|_| {
+            let names: Vec<_> = DEFAULT_ENCODERS.iter().map(|x| x.0).collect();
+            names.join(",")
+        });
+
+        parse_encoders(&list)
+            .into_iter()
+            .find(|x| AVCodec::find_encoder_by_name(&x.name).is_some())
+    };
+}
+
+fn parse_encoders(list: &str) -> Vec<Encoder> {
+    let cstring = |x: &str| CString::new(x).expect("Parsing VIDEO_ENCODERS failed");
+
+    let mut encoders = Vec::new();
+    for x in list.split(',').map(str::trim).filter(|x| !x.is_empty()) {
+        let mut iter = x.split(':');
+        let name = iter.next().unwrap();
+        let mut options: Vec<_> = iter
+            .map(|x| x.split_once('=').expect("Parsing VIDEO_ENCODERS failed"))
+            .map(|(key, value)| (cstring(key), cstring(value)))
+            .collect();
+        if options.is_empty() {
+            if let Some((_, default)) = DEFAULT_ENCODERS.iter().find(|x| x.0 == name) {
+                options = default
+                    .iter()
+                    .map(|(key, value)| (cstring(key), cstring(value)))
+                    .collect();
+            }
+        }
+
+        encoders.push(Encoder {
+            name: cstring(name),
+            options,
+        });
+    }
+
+    encoders
+}
+
+pub fn video_encoder() -> Option<&'static str> {
+    VIDEO_ENCODER.as_ref().and_then(|x| x.name.to_str().ok())
+}
 
 struct SurfaceIter {
     surface: Surface,
@@ -29,15 +113,60 @@ struct SurfaceIter {
     color: Color,
 }
 
-#[allow(clippy::type_complexity)]
 struct AVFrameIter {
     frame_buffer: AVFrame,
     format_context: AVFormatContextInput,
     decode_context: AVCodecContext,
     stream_index: usize,
     sws_context: Option<SwsContext>,
-    crop_frame: Option<Box<dyn FnMut(&mut AVFrame) -> i32>>,
     color: Color,
+    align: Option<Align>,
+    show_detect: bool,
+    track: Option<FaceTrack>,
+    start: i64,
+    end: i64,
+    rotation: i32,
+    rotate_buffer: AVFrame,
+}
+
+#[derive(Clone)]
+struct FaceTrack {
+    length: u32,
+    centers: Vec<(i64, f64, f64)>,
+    detects: Vec<(i64, Vec<Rect>)>,
+}
+
+impl FaceTrack {
+    fn center_at(&self, pts: i64) -> (f64, f64) {
+        let i = self.centers.partition_point(|x| x.0 <= pts);
+        if i == 0 || i == self.centers.len() {
+            let (_, x, y) = self.centers[min(i, self.centers.len() - 1)];
+            return (x, y);
+        }
+
+        let (a, b) = (self.centers[i - 1], self.centers[i]);
+        let t = (pts - a.0) as f64 / (b.0 - a.0) as f64;
+        (a.1 + (b.1 - a.1) * t, a.2 + (b.2 - a.2) * t)
+    }
+
+    fn rect_at(&self, pts: i64, width: u32, height: u32) -> Rect {
+        let (x, y) = self.center_at(pts);
+        let half = self.length as f64 / 2.0;
+
+        Rect {
+            x: (x - half).clamp(0.0, (width - self.length) as f64) as _,
+            y: (y - half).clamp(0.0, (height - self.length) as f64) as _,
+            width: self.length,
+            height: self.length,
+        }
+    }
+
+    fn detects_at(&self, pts: i64) -> &[Rect] {
+        match self.detects.partition_point(|x| x.0 <= pts) {
+            0 => &[],
+            i => &self.detects[i - 1].1,
+        }
+    }
 }
 
 unsafe fn frame_set_color(frame: &mut AVFrame, color: Color) {
@@ -52,6 +181,115 @@ unsafe fn frame_set_color(frame: &mut AVFrame, color: Color) {
     }
 }
 
+fn scaled_size(width: i32, height: i32) -> (i32, i32) {
+    let length = max(width, height);
+    let scaled = length.clamp(MIN_VIDEO_SIZE, *MAX_VIDEO_SIZE);
+    let even = |x: i32| x + x % 2;
+
+    (
+        even(width * scaled / length),
+        even(height * scaled / length),
+    )
+}
+
+unsafe fn rotate_frame(src: &AVFrame, dst: &mut AVFrame, rotation: i32) {
+    let (width, height) = (src.width as usize, src.height as usize);
+    let src_linesize = src.linesize[0] as usize;
+    let dst_linesize = dst.linesize[0] as usize;
+    let src_data = unsafe { slice::from_raw_parts(src.data[0], src_linesize * height) };
+    let dst_data =
+        unsafe { slice::from_raw_parts_mut(dst.data_mut()[0], dst_linesize * dst.height as usize) };
+
+    for dst_y in 0..dst.height as usize {
+        for dst_x in 0..dst.width as usize {
+            let (src_x, src_y) = match rotation {
+                90 => (dst_y, height - 1 - dst_x),
+                180 => (width - 1 - dst_x, height - 1 - dst_y),
+                270 => (width - 1 - dst_y, dst_x),
+                _ => (dst_x, dst_y),
+            };
+            let src_offset = src_y * src_linesize + src_x * 4;
+            let dst_offset = dst_y * dst_linesize + dst_x * 4;
+            dst_data[dst_offset..dst_offset + 4]
+                .copy_from_slice(&src_data[src_offset..src_offset + 4]);
+        }
+    }
+}
+
+unsafe fn crop_frame(frame: &mut AVFrame, rect: &Rect) -> i32 {
+    let frame: &mut ffi::AVFrame = unsafe { &mut *frame.as_mut_ptr() };
+    frame.crop_left = rect.x as _;
+    frame.crop_right = (frame.width as u32 - rect.x - rect.width) as _;
+    frame.crop_top = rect.y as _;
+    frame.crop_bottom = (frame.height as u32 - rect.y - rect.height) as _;
+
+    unsafe { ffi::av_frame_apply_cropping(frame, ffi::AV_FRAME_CROP_UNALIGNED as _) }
+}
+
+unsafe fn frame_draw_rect(frame: &mut AVFrame, rect: &Rect, color: [u8; 4], thickness: u32) {
+    let width = frame.width as u32;
+    let height = frame.height as u32;
+    let linesize = frame.linesize[0] as usize;
+    let data =
+        unsafe { slice::from_raw_parts_mut(frame.data_mut()[0], linesize * height as usize) };
+    let mut draw_pixel = |x: u32, y: u32| {
+        if x < width && y < height {
+            let offset = y as usize * linesize + x as usize * 4;
+            data[offset..offset + 4].copy_from_slice(&color);
+        }
+    };
+
+    for i in 0..thickness {
+        let (left, top) = (rect.x + i, rect.y + i);
+        let right = (rect.x + rect.width).saturating_sub(i + 1);
+        let bottom = (rect.y + rect.height).saturating_sub(i + 1);
+        if left > right || top > bottom {
+            break;
+        }
+        for x in left..=right {
+            draw_pixel(x, top);
+            draw_pixel(x, bottom);
+        }
+        for y in top..=bottom {
+            draw_pixel(left, y);
+            draw_pixel(right, y);
+        }
+    }
+}
+
+fn scale_rect(rect: &Rect, scale: f64) -> Rect {
+    Rect {
+        x: (rect.x as f64 * scale) as _,
+        y: (rect.y as f64 * scale) as _,
+        width: (rect.width as f64 * scale) as _,
+        height: (rect.height as f64 * scale) as _,
+    }
+}
+
+fn display_size(width: u32, height: u32, rotation: i32) -> (u32, u32) {
+    match rotation {
+        90 | 270 => (height, width),
+        _ => (width, height),
+    }
+}
+
+fn unrotate_rect(rect: Rect, width: u32, height: u32, rotation: i32) -> Rect {
+    let length = rect.width;
+    let (x, y) = match rotation {
+        90 => (rect.y, height - rect.x - length),
+        180 => (width - rect.x - length, height - rect.y - length),
+        270 => (width - rect.y - length, rect.x),
+        _ => (rect.x, rect.y),
+    };
+
+    Rect {
+        x,
+        y,
+        width: length,
+        height: length,
+    }
+}
+
 trait FrameIter {
     fn next_frame(&mut self) -> Result<Option<&mut AVFrame>, Error>;
     fn time_base(&self) -> AVRational;
@@ -92,8 +330,8 @@ impl FrameIter for SurfaceIter {
     }
 }
 
-impl FrameIter for AVFrameIter {
-    fn next_frame(&mut self) -> Result<Option<&mut AVFrame>, Error> {
+impl AVFrameIter {
+    fn decode_frame(&mut self) -> Result<Option<AVFrame>, Error> {
         loop {
             let packet = loop {
                 match self.format_context.read_packet()? {
@@ -108,94 +346,15 @@ impl FrameIter for AVFrameIter {
             };
 
             match self.decode_context.receive_frame() {
-                Ok(mut frame) => {
-                    let time_base = self.time_base();
-                    if (frame.pts as i32) * time_base.num > 10 * time_base.den {
+                Ok(frame) => {
+                    if frame.pts > self.end {
                         return Ok(None);
                     }
-                    if frame.pts == self.frame_buffer.pts {
+                    if frame.pts < self.start || frame.pts - self.start == self.frame_buffer.pts {
                         continue;
                     }
 
-                    if self.sws_context.is_none()
-                        && (frame.width != frame.height
-                            || frame.format == ffi::AV_PIX_FMT_YUVA420P
-                            || min(frame.width, frame.height) % 2 != 0)
-                    {
-                        let dst_format = if frame.format == ffi::AV_PIX_FMT_YUVA420P {
-                            ffi::AV_PIX_FMT_BGRA
-                        } else {
-                            ffi::AV_PIX_FMT_YUV420P
-                        };
-
-                        let diff = frame.width - frame.height;
-                        let (x, y, length) = if diff > 0 {
-                            (diff / 2, 0, frame.height)
-                        } else {
-                            (0, -diff / 2, frame.width)
-                        };
-
-                        let dst_length = length + length % 2;
-                        let sws_context = SwsContext::get_context(
-                            length,
-                            length,
-                            frame.format,
-                            dst_length,
-                            dst_length,
-                            dst_format,
-                            0,
-                            None,
-                            None,
-                            None,
-                        )
-                        .ok_or("Failed to get sws_context")?;
-                        self.sws_context = Some(sws_context);
-
-                        if frame.width != frame.height {
-                            let crop_frame = move |frame: &mut AVFrame| {
-                                if frame.width == frame.height {
-                                    return 0;
-                                }
-
-                                let frame: &mut ffi::AVFrame = unsafe { &mut *frame.as_mut_ptr() };
-                                frame.crop_left = x as _;
-                                frame.crop_right = (frame.width - x - length) as _;
-                                frame.crop_top = y as _;
-                                frame.crop_bottom = (frame.height - y - length) as _;
-
-                                unsafe {
-                                    ffi::av_frame_apply_cropping(
-                                        frame,
-                                        ffi::AV_FRAME_CROP_UNALIGNED as _,
-                                    )
-                                }
-                            };
-                            self.crop_frame = Some(Box::new(crop_frame));
-                        }
-
-                        self.frame_buffer.set_format(dst_format);
-                        self.frame_buffer.set_width(dst_length);
-                        self.frame_buffer.set_height(dst_length);
-                        self.frame_buffer.alloc_buffer()?;
-                    }
-
-                    if let Some(sws_ctx) = &mut self.sws_context {
-                        if let Some(crop_frame) = self.crop_frame.as_mut() {
-                            if crop_frame(&mut frame) != 0 || frame.width != frame.height {
-                                return Err("Failed to crop frame".into());
-                            }
-                        };
-                        self.frame_buffer.make_writable()?;
-                        sws_ctx.scale_frame(&frame, 0, frame.height, &mut self.frame_buffer)?;
-                        if self.frame_buffer.format == ffi::AV_PIX_FMT_BGRA {
-                            unsafe { frame_set_color(&mut self.frame_buffer, self.color) };
-                        }
-                        self.frame_buffer.set_pts(frame.pts);
-                    } else {
-                        self.frame_buffer = frame;
-                    }
-
-                    break Ok(Some(&mut self.frame_buffer));
+                    break Ok(Some(frame));
                 }
                 Err(RsmpegError::DecoderDrainError) => {}
                 Err(RsmpegError::DecoderFlushedError) => break Ok(None),
@@ -204,6 +363,187 @@ impl FrameIter for AVFrameIter {
         }
     }
 
+    fn track_faces(mut self) -> Result<Option<FaceTrack>, Error> {
+        let interval = seconds_to_ts(TRACK_INTERVAL, self.time_base()).max(1);
+        let mut next_pts = i64::MIN;
+        let mut length = 0;
+        let mut samples = Vec::new();
+        let mut detects = Vec::new();
+        while let Some(frame) = self.decode_frame()? {
+            if frame.width == frame.height && !self.show_detect {
+                return Ok(None);
+            }
+            if frame.pts < next_pts {
+                continue;
+            }
+            next_pts = frame.pts + interval;
+
+            let image = rotate_image(frame_to_image(&frame)?, self.rotation);
+            let detect = detect_faces(&image)?;
+            if let Some(x) = select_face(&image, &detect) {
+                length = max(length, x.width);
+                let half = x.width as f64 / 2.0;
+                samples.push((frame.pts, x.x as f64 + half, x.y as f64 + half));
+            }
+            detects.push((frame.pts, detect));
+        }
+
+        if samples.is_empty() {
+            return Ok(None);
+        }
+
+        let centers = (0..samples.len())
+            .map(|i| {
+                let range = &samples[i.saturating_sub(TRACK_SMOOTHING)
+                    ..min(i + TRACK_SMOOTHING + 1, samples.len())];
+                let count = range.len() as f64;
+                let x = range.iter().map(|x| x.1).sum::<f64>() / count;
+                let y = range.iter().map(|x| x.2).sum::<f64>() / count;
+                (samples[i].0, x, y)
+            })
+            .collect();
+
+        Ok(Some(FaceTrack {
+            length,
+            centers,
+            detects,
+        }))
+    }
+
+    fn crop_rect(&self, frame: &AVFrame) -> Rect {
+        let width = frame.width as u32;
+        let height = frame.height as u32;
+        if width == height {
+            return square_rect(width, height, &Align::Center);
+        }
+
+        let (display_width, display_height) = display_size(width, height, self.rotation);
+        let rect = match (&self.track, &self.align) {
+            (Some(track), _) => track.rect_at(frame.pts, display_width, display_height),
+            (None, Some(align)) => square_rect(display_width, display_height, align),
+            (None, None) => square_rect(display_width, display_height, &Align::Center),
+        };
+
+        unrotate_rect(rect, width, height, self.rotation)
+    }
+
+    fn draw_detect(&mut self, pts: i64, width: u32, height: u32) {
+        let track = match &self.track {
+            Some(x) => x,
+            None => return,
+        };
+
+        let (display_width, display_height) = display_size(width, height, self.rotation);
+        let scale = self.frame_buffer.width as f64 / display_width as f64;
+        for i in track.detects_at(pts) {
+            let rect = scale_rect(i, scale);
+            let thickness = max(rect.width / 64, 1);
+            unsafe { frame_draw_rect(&mut self.frame_buffer, &rect, [0, 0, 0, 0xff], thickness) };
+        }
+
+        let rect = scale_rect(&track.rect_at(pts, display_width, display_height), scale);
+        let thickness = rect.width / 128 + 1;
+        unsafe { frame_draw_rect(&mut self.frame_buffer, &rect, [0, 0, 0xff, 0xff], thickness) };
+    }
+}
+
+impl FrameIter for AVFrameIter {
+    fn next_frame(&mut self) -> Result<Option<&mut AVFrame>, Error> {
+        let mut frame = match self.decode_frame()? {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+        let width = frame.width as u32;
+        let height = frame.height as u32;
+
+        if self.sws_context.is_none()
+            && (width != height
+                || frame.format == ffi::AV_PIX_FMT_YUVA420P
+                || self.rotation != 0
+                || self.show_detect
+                || scaled_size(frame.width, frame.height) != (frame.width, frame.height))
+        {
+            let dst_format = if frame.format == ffi::AV_PIX_FMT_YUVA420P
+                || self.rotation != 0
+                || self.show_detect
+            {
+                ffi::AV_PIX_FMT_BGRA
+            } else {
+                ffi::AV_PIX_FMT_YUV420P
+            };
+
+            let (src_width, src_height, dst_width, dst_height) = if self.show_detect {
+                let (display_width, display_height) = display_size(width, height, self.rotation);
+                let (x, y) = scaled_size(display_width as _, display_height as _);
+                let (dst_width, dst_height) = display_size(x as _, y as _, self.rotation);
+                (frame.width, frame.height, dst_width as _, dst_height as _)
+            } else {
+                let length = self.crop_rect(&frame).width as i32;
+                let (dst_length, _) = scaled_size(length, length);
+                (length, length, dst_length, dst_length)
+            };
+
+            let sws_context = SwsContext::get_context(
+                src_width,
+                src_height,
+                frame.format,
+                dst_width,
+                dst_height,
+                dst_format,
+                0,
+                None,
+                None,
+                None,
+            )
+            .ok_or("Failed to get sws_context")?;
+            self.sws_context = Some(sws_context);
+
+            let (display_width, display_height) =
+                display_size(dst_width as _, dst_height as _, self.rotation);
+            self.frame_buffer.set_format(dst_format);
+            self.frame_buffer.set_width(display_width as _);
+            self.frame_buffer.set_height(display_height as _);
+            self.frame_buffer.alloc_buffer()?;
+
+            if self.rotation != 0 {
+                self.rotate_buffer.set_format(dst_format);
+                self.rotate_buffer.set_width(dst_width);
+                self.rotate_buffer.set_height(dst_height);
+                self.rotate_buffer.alloc_buffer()?;
+            }
+        }
+
+        let pts = frame.pts - self.start;
+        let crop = (!self.show_detect && width != height).then(|| self.crop_rect(&frame));
+        if let Some(sws_ctx) = &mut self.sws_context {
+            if let Some(rect) = crop {
+                if unsafe { crop_frame(&mut frame, &rect) } != 0 || frame.width != frame.height {
+                    return Err("Failed to crop frame".into());
+                }
+            };
+            self.frame_buffer.make_writable()?;
+            if self.rotation != 0 {
+                let rotate_buffer = &mut self.rotate_buffer;
+                rotate_buffer.make_writable()?;
+                sws_ctx.scale_frame(&frame, 0, frame.height, rotate_buffer)?;
+                unsafe { rotate_frame(rotate_buffer, &mut self.frame_buffer, self.rotation) };
+            } else {
+                sws_ctx.scale_frame(&frame, 0, frame.height, &mut self.frame_buffer)?;
+            }
+
+            if self.show_detect {
+                self.draw_detect(frame.pts, width, height);
+            } else if self.frame_buffer.format == ffi::AV_PIX_FMT_BGRA {
+                unsafe { frame_set_color(&mut self.frame_buffer, self.color) };
+            }
+        } else {
+            self.frame_buffer = frame;
+        }
+        self.frame_buffer.set_pts(pts);
+
+        Ok(Some(&mut self.frame_buffer))
+    }
+
     fn time_base(&self) -> AVRational {
         self.decode_context.time_base
     }
@@ -223,7 +563,10 @@ fn read_animation(data: &[u8], cache_key: &str) -> Result<Animation, Error> {
 }
 
 fn decode_lottie(animation: Animation, color: Color) -> Result<SurfaceIter, Error> {
-    let size = animation.size();
+    let mut size = animation.size();
+    let (width, height) = scaled_size(size.width as _, size.height as _);
+    size.width = width as _;
+    size.height = height as _;
     let totalframe = animation.totalframe();
     let mut frame_buffer = AVFrame::new();
     frame_buffer.set_format(ffi::AV_PIX_FMT_BGRA);
@@ -242,11 +585,26 @@ fn decode_lottie(animation: Animation, color: Color) -> Result<SurfaceIter, Erro
     })
 }
 
+fn trim_window(opt: &Opt) -> Result<(f64, f64), Error> {
+    let start = opt.start.unwrap_or(0.0);
+    let end = match (opt.end, opt.length) {
+        (Some(x), _) => x,
+        (None, Some(x)) => start + x,
+        (None, None) => start + MAX_DURATION,
+    };
+    if end <= start {
+        return "结束时间必须晚于开始时间".result();
+    }
+
+    Ok((start, end.min(start + MAX_DURATION)))
+}
+
 fn decode_video(
-    input_format_context: AVFormatContextInput,
-    color: Color,
+    mut input_format_context: AVFormatContextInput,
+    opt: &Opt,
+    track: Option<FaceTrack>,
 ) -> Result<AVFrameIter, Error> {
-    let (stream_index, decode_context) = {
+    let (stream_index, rotation, decode_context) = {
         let (stream_index, mut decoder) = input_format_context
             .find_best_stream(ffi::AVMEDIA_TYPE_VIDEO)?
             .ok_or("Failed to find the best stream")?;
@@ -268,9 +626,17 @@ fn decode_video(
         decode_context.set_framerate(stream.avg_frame_rate);
         decode_context.set_time_base(stream.time_base);
 
-        (stream_index, decode_context)
+        (stream_index, stream_rotation(stream), decode_context)
     };
 
+    let (start, end) = trim_window(opt)?;
+    let time_base = decode_context.time_base;
+    let start = seconds_to_ts(start, time_base);
+    let end = seconds_to_ts(end, time_base);
+    if start > 0 {
+        seek_frame(&mut input_format_context, stream_index, start)?;
+    }
+
     let mut frame_buffer = AVFrame::new();
     frame_buffer.set_pts(-1);
 
@@ -280,8 +646,14 @@ fn decode_video(
         decode_context,
         stream_index,
         sws_context: None,
-        crop_frame: None,
-        color,
+        color: opt.color,
+        align: opt.align,
+        show_detect: opt.show_detect,
+        track,
+        start,
+        end,
+        rotation,
+        rotate_buffer: AVFrame::new(),
     };
 
     Ok(ret)
@@ -373,8 +745,9 @@ fn output_format_context() -> Result<(AVFormatContextOutput, Arc<Mutex<Cursor<Ve
     Ok((output_format_context, data))
 }
 
-fn encode_mp4<S: FrameIter>(mut src: S) -> Result<Vec<u8>, Error> {
-    let buffer = {
+fn encode_mp4<S: FrameIter>(mut src: S, bit_rate: Option<i64>) -> Result<(Vec<u8>, f64), Error> {
+    let mut last_pts = 0;
+    let (buffer, time_base, framerate) = {
         let time_base = src.time_base();
         let framerate = src.framerate();
         let first_frame = src.next_frame()?.ok_or("Failed to get first frame")?;
@@ -383,16 +756,27 @@ fn encode_mp4<S: FrameIter>(mut src: S) -> Result<Vec<u8>, Error> {
 
         let (mut output_format_context, buffer) = output_format_context()?;
 
-        let encoder =
-            AVCodec::find_encoder_by_name(c"libx264").ok_or("Failed to find encoder codec")?;
+        let video_encoder = VIDEO_ENCODER.as_ref().ok_or("No video encoder available")?;
+        let encoder = AVCodec::find_encoder_by_name(&video_encoder.name)
+            .ok_or("Failed to find encoder codec")?;
         let mut encode_context = AVCodecContext::new(&encoder);
         encode_context.set_width(width);
         encode_context.set_height(height);
         encode_context.set_time_base(time_base);
         encode_context.set_framerate(framerate);
         encode_context.set_pix_fmt(ffi::AV_PIX_FMT_YUV420P);
-        if unsafe { opt_set(encode_context.priv_data, c"preset", c"slow", 0) }.is_err() {
-            return Err("Failed to set x264 option".into());
+        for (key, value) in &video_encoder.options {
+            let obj = encode_context.as_mut_ptr().cast();
+            if unsafe { opt_set(obj, key, value, ffi::AV_OPT_SEARCH_CHILDREN as _) }.is_err() {
+                return Err(format!("Failed to set encoder option: {key:?}").into());
+            }
+        }
+        if let Some(x) = bit_rate {
+            encode_context.set_bit_rate(x);
+            let encode_context: &mut ffi::AVCodecContext =
+                unsafe { &mut *encode_context.as_mut_ptr() };
+            encode_context.rc_max_rate = x;
+            encode_context.rc_buffer_size = x as _;
         }
         if output_format_context.oformat().flags & ffi::AVFMT_GLOBALHEADER as i32 != 0 {
             encode_context
@@ -432,6 +816,7 @@ fn encode_mp4<S: FrameIter>(mut src: S) -> Result<Vec<u8>, Error> {
             None
         };
         let mut encode_frame = |src_frame: &mut AVFrame| -> Result<(), Error> {
+            last_pts = src_frame.pts;
             let frame_after = if let Some(sws_context) = sws_context.as_mut() {
                 sws_context.scale_frame(src_frame, 0, height, &mut dst_frame)?;
                 dst_frame.set_pts(src_frame.pts);
@@ -455,7 +840,7 @@ fn encode_mp4<S: FrameIter>(mut src: S) -> Result<Vec<u8>, Error> {
         encode_write_frame(None, &mut encode_context, &mut output_format_context, 0)?;
         output_format_context.write_trailer()?;
 
-        buffer
+        (buffer, time_base, framerate)
     };
 
     let ret = Arc::into_inner(buffer)
@@ -463,7 +848,38 @@ fn encode_mp4<S: FrameIter>(mut src: S) -> Result<Vec<u8>, Error> {
         .into_inner()?
         .into_inner();
 
-    Ok(ret)
+    let mut duration = last_pts as f64 * time_base.num as f64 / time_base.den as f64;
+    if framerate.num > 0 {
+        duration += framerate.den as f64 / framerate.num as f64;
+    }
+
+    Ok((ret, duration.max(f64::EPSILON)))
+}
+
+fn encode_mp4_within_budget<S: FrameIter>(
+    mut new_src: impl FnMut() -> Result<S, Error>,
+) -> Result<Vec<u8>, Error> {
+    let mut bit_rate = None;
+    for _ in 0..MAX_ENCODE_ATTEMPTS {
+        let (data, duration) = encode_mp4(new_src()?, bit_rate)?;
+        if data.len() <= *MAX_VIDEO_FILESIZE {
+            return Ok(data);
+        }
+
+        let actual = data.len() as f64 * 8.0 / duration;
+        let budget = *MAX_VIDEO_FILESIZE as f64 * 8.0 / duration;
+        let target = bit_rate.map_or(actual, |x| x as f64) * budget / actual * 0.9;
+        if target < MIN_BIT_RATE as f64 {
+            break;
+        }
+        bit_rate = Some(target as i64);
+    }
+
+    format!(
+        "视频头像无法压缩到 {} KB 以内, 请缩短时长后重试",
+        *MAX_VIDEO_FILESIZE / 1024
+    )
+    .result()
 }
 
 fn encode_write_frame(
@@ -493,16 +909,188 @@ fn encode_write_frame(
     Ok(())
 }
 
+/// Whether the video can be used as an avatar as is, without re-encoding.
+pub fn is_avatar_ready(data: &[u8]) -> Result<bool, Error> {
+    if data.len() > *MAX_VIDEO_FILESIZE {
+        return Ok(false);
+    }
+
+    let input_format_context = input_format_context(data.to_vec())?;
+    let Some((stream_index, _)) = input_format_context.find_best_stream(ffi::AVMEDIA_TYPE_VIDEO)?
+    else {
+        return Ok(false);
+    };
+    let stream = input_format_context.streams().get(stream_index).unwrap();
+    let codecpar = stream.codecpar();
+    let max_duration = (MAX_DURATION * ffi::AV_TIME_BASE as f64) as i64;
+
+    Ok(codecpar.codec_id == ffi::AV_CODEC_ID_H264
+        && codecpar.width == codecpar.height
+        && (MIN_VIDEO_SIZE..=*MAX_VIDEO_SIZE).contains(&codecpar.width)
+        && stream_rotation(stream) == 0
+        && input_format_context.duration <= max_duration)
+}
+
 pub fn tgs_to_mp4(data: Vec<u8>, cache_key: &str, color: Color) -> Result<Vec<u8>, Error> {
-    let animation = read_animation(&data, cache_key)?;
-    let surface_iter = decode_lottie(animation, color)?;
+    encode_mp4_within_budget(|| decode_lottie(read_animation(&data, cache_key)?, color))
+}
+
+pub fn video_to_mp4(data: Vec<u8>, opt: &Opt) -> Result<Vec<u8>, Error> {
+    let track = match opt.align {
+        Some(_) => None,
+        None => decode_video(input_format_context(data.clone())?, opt, None)?.track_faces()?,
+    };
 
-    encode_mp4(surface_iter)
+    encode_mp4_within_budget(|| {
+        let format_context = input_format_context(data.clone())?;
+        decode_video(format_context, opt, track.clone())
+    })
 }
 
-pub fn video_to_mp4(data: Vec<u8>, color: Color) -> Result<Vec<u8>, Error> {
-    let format_context = input_format_context(data)?;
-    let frame_iter = decode_video(format_context, color)?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::settings::Settings;
+    use image::{Rgba, RgbaImage};
+
+    fn opt(opt: &str) -> Opt {
+        Opt::parse(opt, &Settings::default()).unwrap()
+    }
+
+    #[test]
+    fn trim_window_defaults_to_max_duration() {
+        assert_eq!(trim_window(&opt("")).unwrap(), (0.0, MAX_DURATION));
+        assert_eq!(
+            trim_window(&opt("start=5")).unwrap(),
+            (5.0, 5.0 + MAX_DURATION)
+        );
+        assert_eq!(trim_window(&opt("end=30")).unwrap(), (0.0, MAX_DURATION));
+        assert_eq!(
+            trim_window(&opt("start=2 len=20")).unwrap(),
+            (2.0, 2.0 + MAX_DURATION)
+        );
+    }
+
+    #[test]
+    fn trim_window_prefers_end_over_len() {
+        assert_eq!(trim_window(&opt("start=1 end=3")).unwrap(), (1.0, 3.0));
+        assert_eq!(trim_window(&opt("start=1 len=2")).unwrap(), (1.0, 3.0));
+        assert_eq!(
+            trim_window(&opt("start=1 end=4 len=1")).unwrap(),
+            (1.0, 4.0)
+        );
+    }
+
+    #[test]
+    fn trim_window_rejects_empty_windows() {
+        for x in ["start=3 end=3", "start=5 end=1", "len=0"] {
+            let e = trim_window(&opt(x)).unwrap_err();
+            assert_eq!(e.to_string(), "结束时间必须晚于开始时间", "{x}");
+        }
+    }
 
-    encode_mp4(frame_iter)
+    #[test]
+    fn scaled_size_fits_the_video_size_limits() {
+        assert_eq!(scaled_size(640, 480), (640, 480));
+        assert_eq!(scaled_size(1920, 1080), (800, 450));
+        assert_eq!(scaled_size(1080, 1920), (450, 800));
+        assert_eq!(scaled_size(100, 50), (160, 80));
+    }
+
+    #[test]
+    fn scaled_size_is_even_and_within_max() {
+        assert_eq!(scaled_size(1000, 999), (800, 800));
+        assert_eq!(scaled_size(321, 161), (322, 162));
+        for (width, height) in [(1001, 333), (777, 1555), (161, 161), (3, 1000)] {
+            let (x, y) = scaled_size(width, height);
+            assert!(x % 2 == 0 && y % 2 == 0, "{width}x{height}");
+            assert!(x.max(y) <= *MAX_VIDEO_SIZE, "{width}x{height}");
+        }
+    }
+
+    fn bgra_frame(width: i32, height: i32) -> AVFrame {
+        let mut frame = AVFrame::new();
+        frame.set_format(ffi::AV_PIX_FMT_BGRA);
+        frame.set_width(width);
+        frame.set_height(height);
+        frame.alloc_buffer().unwrap();
+        frame
+    }
+
+    /// A frame whose pixels all differ, so any misplaced pixel shows up.
+    fn pattern_frame(width: i32, height: i32) -> AVFrame {
+        let mut frame = bgra_frame(width, height);
+        let len = frame.linesize[0] as usize * height as usize;
+        let data = unsafe { slice::from_raw_parts_mut(frame.data_mut()[0], len) };
+        for (i, x) in data.chunks_exact_mut(4).enumerate() {
+            x.copy_from_slice(&[i as u8, (i / 3) as u8, (i / 7) as u8, 0xff]);
+        }
+        frame
+    }
+
+    /// A black image with the pixels of `rect` white.
+    fn marked_image(width: u32, height: u32, rect: &Rect) -> RgbaImage {
+        RgbaImage::from_fn(width, height, |x, y| {
+            let inside = (rect.x..rect.x + rect.width).contains(&x)
+                && (rect.y..rect.y + rect.height).contains(&y);
+            Rgba(if inside { [0xff; 4] } else { [0, 0, 0, 0xff] })
+        })
+    }
+
+    /// The bounding box of the white pixels.
+    fn marked_rect(img: &RgbaImage) -> Rect {
+        let marked: Vec<_> = img
+            .enumerate_pixels()
+            .filter(|x| x.2[0] == 0xff)
+            .map(|(x, y, _)| (x, y))
+            .collect();
+        let left = marked.iter().map(|x| x.0).min().unwrap();
+        let top = marked.iter().map(|x| x.1).min().unwrap();
+        let right = marked.iter().map(|x| x.0).max().unwrap();
+        let bottom = marked.iter().map(|x| x.1).max().unwrap();
+        Rect {
+            x: left,
+            y: top,
+            width: right - left + 1,
+            height: bottom - top + 1,
+        }
+    }
+
+    #[test]
+    fn unrotate_rect_round_trips_through_rotate_image() {
+        let (width, height) = (40, 30);
+        for (x, y) in [(0, 0), (3, 5), (28, 18)] {
+            let rect = Rect {
+                x,
+                y,
+                width: 12,
+                height: 12,
+            };
+            for rotation in [0, 90, 180, 270] {
+                let rotated = rotate_image(marked_image(width, height, &rect), rotation);
+                let x = unrotate_rect(marked_rect(&rotated), width, height, rotation);
+                assert_eq!(
+                    (x.x, x.y, x.width, x.height),
+                    (rect.x, rect.y, rect.width, rect.height),
+                    "{rotation}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn rotate_frame_matches_rotate_image() {
+        let (width, height) = (16, 8);
+        let src = pattern_frame(width, height);
+        let image = frame_to_image(&src).unwrap();
+        for rotation in [90, 180, 270] {
+            let (dst_width, dst_height) = display_size(width as _, height as _, rotation);
+            let mut dst = bgra_frame(dst_width as _, dst_height as _);
+            unsafe { rotate_frame(&src, &mut dst, rotation) };
+            let expected = rotate_image(image.clone(), rotation);
+            assert_eq!(expected.dimensions(), (dst_width, dst_height), "{rotation}");
+            assert!(frame_to_image(&dst).unwrap() == expected, "{rotation}");
+        }
+    }
 }